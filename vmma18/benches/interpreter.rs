@@ -0,0 +1,108 @@
+/* Synopsis: Timing benchmarks for the interpreter loop
+ *
+ * Runs small hand-assembled programs through run_capture, so no real I/O is
+ * involved, and prints the mean time per run for each. The programs cover
+ * the main paths of the run loop: arithmetic and branches, calls and
+ * returns, and Print with its output buffering.
+ *
+ * Usage: cargo bench [name filter]
+ */
+
+use std::env::args;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use vmproject::run_capture;
+
+const MAGIC: u32 = 0xEFBE_ADDE;
+
+// Each benchmark runs for at least this long before its mean is taken
+const MIN_TIME: Duration = Duration::from_millis(500);
+
+// Count 100000 down to 0: push, subtract and a backward ifnz per iteration
+const ARITHMETIC_LOOP: &[u32] = &[
+    MAGIC,
+    0xF001_86A0, // push 100000
+    0xF000_0001, // loop: push 1
+    0x2100_0000, //   sub
+    0x92FF_FFF8, //   ifnz loop
+    0x0000_0000, // exit 0
+];
+
+// Recurse 200 calls deep: f(n) { if n != 0 { f(n - 1) } }
+const RECURSIVE_CALLS: &[u32] = &[
+    MAGIC,
+    0xF000_00C8, // push 200
+    0x5000_0008, // call f
+    0x0000_0000, // exit 0
+    0xC000_0004, // f: dup 4 (the argument, below the return address)
+    0x9000_0018, //   ifez done
+    0x1000_0004, //   pop 4
+    0xC000_0004, //   dup 4
+    0xF000_0001, //   push 1
+    0x2100_0000, //   sub
+    0x5FFF_FFE8, //   call f
+    0x1000_0004, // done: pop 4
+    0x6000_0000, //   return
+];
+
+// Print 1000 down to 1, one number per line
+const OUTPUT_HEAVY: &[u32] = &[
+    MAGIC,
+    0xF000_03E8, // push 1000
+    0xD000_0000, // loop: print 0
+    0xF000_0001, //   push 1
+    0x2100_0000, //   sub
+    0x92FF_FFF4, //   ifnz loop
+    0x0000_0000, // exit 0
+];
+
+fn main() {
+    // cargo bench passes --bench; anything else narrows the benchmarks by name
+    let filter = args().skip(1).find(|arg| !arg.starts_with("--"));
+
+    let benchmarks: [(&str, fn()); 3] = [
+        ("arithmetic_loop", arithmetic_loop),
+        ("recursive_calls", recursive_calls),
+        ("output_heavy", output_heavy),
+    ];
+    for (name, benchmark) in benchmarks {
+        if filter.as_ref().is_none_or(|filter| name.contains(filter.as_str())) {
+            time(name, benchmark);
+        }
+    }
+}
+
+fn arithmetic_loop() {
+    run(ARITHMETIC_LOOP);
+}
+
+fn recursive_calls() {
+    run(RECURSIVE_CALLS);
+}
+
+fn output_heavy() {
+    run(OUTPUT_HEAVY);
+}
+
+// Run one program to completion, checking that it exited cleanly
+fn run(program: &[u32]) {
+    let (code, output) = run_capture(black_box(program), &[]).expect("benchmark program failed");
+    assert_eq!(code, 0);
+    black_box(output);
+}
+
+// Repeat a benchmark until MIN_TIME has passed and report the mean per run
+fn time(name: &str, benchmark: fn()) {
+    benchmark();
+
+    let start = Instant::now();
+    let mut runs = 0u32;
+    while start.elapsed() < MIN_TIME {
+        benchmark();
+        runs += 1;
+    }
+
+    let mean = start.elapsed() / runs;
+    println!("{:<16} {:>12.3?}/run ({} runs)", name, mean, runs);
+}
@@ -0,0 +1,254 @@
+/* Synopsis: Randomized fuzz target for the decoder and run loop.
+ *
+ * Feeds random programs and mutated copies of the real programs in marz/ to the
+ * VM under a small cycle budget, with randomly chosen machine options, and
+ * fails if any run panics instead of returning Ok or Err. Some runs are
+ * checkpointed partway through and resumed from the (possibly corrupted)
+ * saved state. The generator is seeded, so a failure can be replayed.
+ *
+ * Usage: cargo run --release -p VMproject-fuzz -- [iterations] [seed] [corpus dir]
+ */
+
+use std::env::args;
+use std::fs;
+use std::io::{self, Cursor};
+use std::panic;
+use std::process;
+
+use vmproject::{FrameCheck, Machine, MachineState, NullHost, StackDirection, VmError, WordWidth};
+
+const MAGIC: u32 = 0xEFBE_ADDE;
+const MAX_CYCLES: u64 = 10_000;
+const MAX_RANDOM_WORDS: usize = 64;
+
+// Header words of a saved machine state, the ones worth corrupting
+const STATE_HEADER_WORDS: usize = 22;
+
+// Input every run reads from: numbers in several bases, text, a blank line
+// and a line of several numbers
+const INPUT: &[u8] = b"12\n-3\n0x1f\n0b101\nhello\n\n2147483648\n7 8 9\n";
+
+// Small xorshift generator so runs are reproducible without extra dependencies
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn word(&mut self) -> u32 {
+        self.next() as u32
+    }
+}
+
+// Machine options for one run
+#[derive(Debug)]
+struct Settings {
+    direction: StackDirection,
+    word_width: WordWidth,
+    frame_check: FrameCheck,
+    strict_branches: bool,
+    trap_null_write: bool,
+    guard_stack: bool,
+    entry: usize,
+
+    // Cycles to run before checkpointing and resuming, and the saved words
+    // (by index) to overwrite with a value before the state is reloaded
+    resume_after: Option<u64>,
+    corrupt: Vec<(usize, u32)>,
+}
+
+impl Settings {
+    fn random(rng: &mut Rng) -> Settings {
+        let resume_after = (rng.below(2) == 0).then(|| rng.below(200) as u64);
+        let corruptions = if resume_after.is_some() { rng.below(4) } else { 0 };
+        let corrupt = (0..corruptions)
+            .map(|_| (rng.below(STATE_HEADER_WORDS + 8), if rng.below(2) == 0 { rng.word() } else { rng.below(2048) as u32 }))
+            .collect();
+
+        Settings {
+            direction: if rng.below(2) == 0 { StackDirection::Down } else { StackDirection::Up },
+            word_width: if rng.below(2) == 0 { WordWidth::Bits32 } else { WordWidth::Bits16 },
+            frame_check: [FrameCheck::Off, FrameCheck::Warn, FrameCheck::Strict][rng.below(3)],
+            strict_branches: rng.below(2) == 0,
+            trap_null_write: rng.below(2) == 0,
+            guard_stack: rng.below(2) == 0,
+            entry: if rng.below(8) == 0 { rng.below(8) } else { 0 },
+            resume_after,
+            corrupt,
+        }
+    }
+}
+
+// Outcome of a batch of runs
+struct Summary {
+    exited: u64,
+    failed: u64,
+}
+
+// A run that panicked, with what is needed to replay it
+struct Panic {
+    iteration: u64,
+    settings: Settings,
+    program: Vec<u32>,
+}
+
+fn main() {
+    let args: Vec<String> = args().collect();
+    let iterations: u64 = args.get(1).and_then(|n| n.parse().ok()).unwrap_or(10_000);
+    let seed: u64 = args.get(2).and_then(|n| n.parse().ok()).unwrap_or(0x5EED);
+    let corpus_dir = args.get(3).cloned()
+        .unwrap_or_else(|| concat!(env!("CARGO_MANIFEST_DIR"), "/../marz").to_string());
+
+    let corpus = load_corpus(&corpus_dir);
+    println!("Loaded {} seed program(s) from {}", corpus.len(), corpus_dir);
+
+    // Panics are reported below with the offending program, not by the default hook
+    panic::set_hook(Box::new(|_| {}));
+
+    match fuzz(iterations, seed, &corpus) {
+        Ok(summary) => println!(
+            "{} run(s): {} exited, {} returned an error, none panicked",
+            iterations, summary.exited, summary.failed
+        ),
+        Err(found) => {
+            println!("Panic on iteration {} (seed {}, {:?}). Program:", found.iteration, seed, found.settings);
+            for (addr, word) in found.program.iter().skip(1).enumerate() {
+                println!("{:04x}: {:08x}", addr, word);
+            }
+            process::exit(1);
+        }
+    }
+}
+
+// Run `iterations` generated programs, stopping at the first that panics
+fn fuzz(iterations: u64, seed: u64, corpus: &[Vec<u32>]) -> Result<Summary, Panic> {
+    let mut rng = Rng(seed.max(1));
+    let mut summary = Summary { exited: 0, failed: 0 };
+
+    for iteration in 0..iterations {
+        let program = generate(&mut rng, corpus);
+        let settings = Settings::random(&mut rng);
+
+        match panic::catch_unwind(|| run_program(&program, &settings)) {
+            Ok(Ok(_)) => summary.exited += 1,
+            Ok(Err(_)) => summary.failed += 1,
+            Err(_) => return Err(Panic { iteration, settings, program }),
+        }
+    }
+    Ok(summary)
+}
+
+// Read every .v file in the corpus directory as a program
+fn load_corpus(dir: &str) -> Vec<Vec<u32>> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().is_some_and(|ext| ext == "v"))
+        .filter_map(|entry| fs::read(entry.path()).ok())
+        .map(|bytes| {
+            bytes.chunks_exact(4)
+                .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect()
+        })
+        .collect()
+}
+
+// Either a fully random program or a seed program with a few corrupted words
+fn generate(rng: &mut Rng, corpus: &[Vec<u32>]) -> Vec<u32> {
+    if corpus.is_empty() || rng.below(2) == 0 {
+        let len = 1 + rng.below(MAX_RANDOM_WORDS);
+        let mut program = vec![MAGIC];
+
+        // A few pushes first, so fewer programs stop at once on an empty stack
+        program.extend((0..rng.below(5)).map(|_| 0xF000_0000 | rng.below(64) as u32));
+        program.extend((0..len).map(|_| random_instruction(rng)));
+        return program;
+    }
+
+    let mut program = corpus[rng.below(corpus.len())].clone();
+    if program.len() < 2 {
+        return program;
+    }
+
+    for _ in 0..1 + rng.below(4) {
+        let idx = 1 + rng.below(program.len() - 1);
+        if rng.below(2) == 0 {
+            program[idx] ^= 1 << rng.below(32);
+        } else {
+            program[idx] = rng.word();
+        }
+    }
+    program
+}
+
+// A random word, or often a random opcode with a small operand, since
+// addresses, sizes and offsets near zero reach far more code than random ones
+fn random_instruction(rng: &mut Rng) -> u32 {
+    match rng.below(2) {
+        0 => rng.word(),
+        _ => (rng.word() & 0xFF00_0000) | rng.below(64) as u32,
+    }
+}
+
+// Load and run one program with preset input and discarded output, resuming
+// it from a checkpoint partway through if the settings ask for one
+fn run_program(program: &[u32], settings: &Settings) -> Result<u8, VmError> {
+    let mut machine = Machine::new(Cursor::new(INPUT), io::sink(), NullHost);
+    machine.direction = settings.direction;
+    machine.entry = settings.entry;
+    configure(&mut machine, settings);
+    machine.load(program)?;
+
+    if let Some(cycles) = settings.resume_after {
+        for _ in 0..cycles {
+            if let Some(code) = machine.step_instruction()? {
+                return Ok(code);
+            }
+        }
+
+        let mut bytes = machine.snapshot().to_bytes();
+        for &(index, value) in &settings.corrupt {
+            bytes[index * 4..index * 4 + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        let state = MachineState::from_bytes(&bytes)?;
+        machine = Machine::from_state(state, Cursor::new(INPUT), io::sink(), NullHost);
+        configure(&mut machine, settings);
+    }
+    machine.run()
+}
+
+// Apply the options a resumed machine does not take from its saved state.
+// Quiet keeps frame check warnings off the fuzzer's output
+fn configure(machine: &mut Machine<Cursor<&[u8]>, io::Sink>, settings: &Settings) {
+    machine.max_cycles = Some(MAX_CYCLES);
+    machine.quiet = true;
+    machine.frame_check = settings.frame_check;
+    machine.word_width = settings.word_width;
+    machine.strict_branches = settings.strict_branches;
+    machine.trap_null_write = settings.trap_null_write;
+    machine.guard_stack = settings.guard_stack;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn random_programs_do_not_panic() {
+        let corpus = load_corpus(concat!(env!("CARGO_MANIFEST_DIR"), "/../marz"));
+        if let Err(found) = fuzz(20_000, 0x5EED, &corpus) {
+            panic!("iteration {} panicked ({:?}) running {:08x?}", found.iteration, found.settings, found.program);
+        }
+    }
+}
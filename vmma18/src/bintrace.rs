@@ -0,0 +1,69 @@
+/* Synopsis: Compact binary instruction trace
+ *
+ * A binary trace file starts with an 8-byte header: the tag "VMTR", the
+ * format version (u16, currently 1) and the record size in bytes (u16,
+ * currently 5). Each executed instruction then adds one little-endian
+ * record: PC (u16), SP (u16) and the opcode nibble (u8).
+ */
+
+use std::fmt;
+
+use crate::VmError;
+
+const TAG: &[u8; 4] = b"VMTR";
+const VERSION: u16 = 1;
+const RECORD_SIZE: usize = 5;
+
+// One traced instruction
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BinaryTraceRecord {
+    pub pc: u16,
+    pub sp: u16,
+    pub opcode: u8,
+}
+
+impl BinaryTraceRecord {
+
+    // Raw record as stored in the file
+    pub fn to_bytes(&self) -> [u8; RECORD_SIZE] {
+        let [pc_low, pc_high] = self.pc.to_le_bytes();
+        let [sp_low, sp_high] = self.sp.to_le_bytes();
+        [pc_low, pc_high, sp_low, sp_high, self.opcode]
+    }
+}
+
+impl fmt::Display for BinaryTraceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04x}: opcode {:x}  sp={}", self.pc, self.opcode, self.sp)
+    }
+}
+
+// Header written once at the start of a binary trace
+pub fn binary_trace_header() -> Vec<u8> {
+    let mut header = TAG.to_vec();
+    header.extend(VERSION.to_le_bytes());
+    header.extend((RECORD_SIZE as u16).to_le_bytes());
+    header
+}
+
+// Parse a whole binary trace file back into records
+pub fn parse_binary_trace(bytes: &[u8]) -> Result<Vec<BinaryTraceRecord>, VmError> {
+    let header = binary_trace_header();
+    if bytes.len() < header.len() || bytes[..header.len()] != header[..] {
+        return Err("not a version 1 binary trace".into());
+    }
+
+    let body = &bytes[header.len()..];
+    if !body.len().is_multiple_of(RECORD_SIZE) {
+        return Err("binary trace ends with a partial record".into());
+    }
+
+    Ok(body
+        .chunks_exact(RECORD_SIZE)
+        .map(|record| BinaryTraceRecord {
+            pc: u16::from_le_bytes([record[0], record[1]]),
+            sp: u16::from_le_bytes([record[2], record[3]]),
+            opcode: record[4],
+        })
+        .collect())
+}
@@ -0,0 +1,93 @@
+/* Synopsis: Differential testing against a reference execution trace
+ *
+ * A reference trace lists the PC and SP expected before each instruction,
+ * one JSON object per line in the same shape as the JSONL trace
+ * (`{"pc":0,"sp":1024,...}`). Running against it single-steps the machine
+ * and stops at the first step whose registers differ from the reference.
+ */
+
+use std::io::{Read, Write};
+
+use crate::{HostEnv, Instruction, Machine, VmError};
+
+// Registers expected before one instruction executes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceStep {
+    pub pc: i16,
+    pub sp: i16,
+}
+
+// Parse a reference trace; blank lines are skipped and other keys ignored
+pub fn parse_trace(text: &str) -> Result<Vec<TraceStep>, VmError> {
+    let mut steps = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let field = |key| {
+            json_int(line, key)
+                .ok_or_else(|| format!("(trace) line {}: missing or bad \"{}\"", number + 1, key))
+        };
+        steps.push(TraceStep { pc: field("pc")?, sp: field("sp")? });
+    }
+
+    Ok(steps)
+}
+
+// Integer value of `"key":` in a flat JSON object
+fn json_int(line: &str, key: &str) -> Option<i16> {
+    let pattern = format!("\"{}\":", key);
+    let rest = line[line.find(&pattern)? + pattern.len()..].trim_start();
+    let end = rest
+        .char_indices()
+        .find(|&(i, c)| !(c.is_ascii_digit() || (i == 0 && c == '-')))
+        .map_or(rest.len(), |(i, _)| i);
+    rest[..end].parse().ok()
+}
+
+impl<R: Read, W: Write, H: HostEnv> Machine<R, W, H> {
+
+    // Run the program, checking PC and SP against the reference before every
+    // instruction. The first divergence is returned as an error describing
+    // the step, both register sets and the machine state at that point.
+    pub fn run_against(&mut self, trace: &[TraceStep]) -> Result<u8, VmError> {
+        for (step, expected) in trace.iter().enumerate() {
+            let actual = TraceStep { pc: self.pc, sp: self.sp };
+            if actual != *expected {
+                return Err(self.divergence(step, expected).into());
+            }
+
+            if let Some(code) = self.step_instruction()? {
+                if step + 1 < trace.len() {
+                    return Err(format!(
+                        "(trace) program exited with {} after {} step(s) but the reference has {}",
+                        code, step + 1, trace.len()).into());
+                }
+                return Ok(code);
+            }
+        }
+
+        Err(format!("(trace) program still running after the reference's {} step(s)", trace.len()).into())
+    }
+
+    // Report for a step whose registers do not match the reference
+    fn divergence(&self, step: usize, expected: &TraceStep) -> String {
+        let instruction = match self.ram.get(self.pc as usize).map(|&word| Instruction::decode(word)) {
+            Some(Ok(instruction)) => instruction.to_string(),
+            Some(Err(e)) => format!("<{}>", e),
+            None => String::from("<PC out of bounds>"),
+        };
+
+        let mut report = format!(
+            "(trace) divergence at step {}: expected pc={} sp={}, got pc={} sp={}\n  next instruction: {}\n  stack depth: {}",
+            step, expected.pc, expected.sp, self.pc, self.sp, instruction, self.depth(),
+        );
+        for offset in 0..self.depth().clamp(0, 8) {
+            if let Ok(addr) = self.stack_addr(offset) {
+                report += &format!("\n  {:04x}: {:08x}", addr, self.ram[addr]);
+            }
+        }
+        report
+    }
+}
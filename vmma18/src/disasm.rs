@@ -0,0 +1,403 @@
+/* Synopsis: Disassembler
+ *
+ * Renders a decoded instruction back into assembly-like text. Branch
+ * offsets are shown in instructions relative to the branch itself, and
+ * stack offsets in bytes, matching how they are written in the .asm files.
+ * Whole programs are listed with synthetic labels (L0, L1, ...) on every
+ * branch and call target.
+ */
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use crate::{split_header, Instruction, VmError};
+
+// List a program (the words after the magic number), one instruction per
+// line, with a label before each branch target and the label's name noted
+// on each branch that reaches it
+pub fn disassemble(program: &[u32]) -> String {
+    listing(program, false)
+}
+
+// The same listing with each instruction's raw word between its address
+// and its text, for diagnosing encoding problems
+pub fn disassemble_raw(program: &[u32]) -> String {
+    listing(program, true)
+}
+
+fn listing(program: &[u32], raw: bool) -> String {
+    // First pass: collect targets that land inside the program
+    let target = |addr: usize, instruction: &Instruction| {
+        let dest = addr as i32 + instruction.branch_offset()?;
+        (0..program.len() as i32).contains(&dest).then_some(dest as usize)
+    };
+    let mut labels = BTreeMap::new();
+    for (addr, &word) in program.iter().enumerate() {
+        if let Some(dest) = Instruction::decode(word).ok().and_then(|inst| target(addr, &inst)) {
+            labels.insert(dest, 0);
+        }
+    }
+    for (number, label) in labels.values_mut().enumerate() {
+        *label = number;
+    }
+
+    // Second pass: print, naming each target
+    let mut listing = String::new();
+    for (addr, &word) in program.iter().enumerate() {
+        if let Some(label) = labels.get(&addr) {
+            listing += &format!("L{}:\n", label);
+        }
+
+        let text = match Instruction::decode(word) {
+            Ok(instruction) => match target(addr, &instruction).and_then(|dest| labels.get(&dest)) {
+                Some(label) => format!("{:<24} # L{}", instruction.to_string(), label),
+                None => instruction.to_string(),
+            },
+            Err(e) => format!("<{}>", e),
+        };
+        if raw {
+            listing += &format!("    {:04x}: {:08x}  {}\n", addr, word, text);
+        } else {
+            listing += &format!("    {:04x}: {}\n", addr, text);
+        }
+    }
+
+    listing
+}
+
+// Addresses of the words that no path from `entry` can reach through
+// fall-through, branches, calls, skips or trap handlers. A program that takes
+// its own PC (pushpc) may compute jumps, so nothing in it is reported. So
+// may a Return that cannot be matched to a Call (see Frame), since `push N;
+// return 0` jumps to N.
+pub fn find_dead_code(program: &[u32], entry: usize) -> Vec<usize> {
+    let decoded: Vec<Option<Instruction>> = program.iter().map(|&word| Instruction::decode(word).ok()).collect();
+    if decoded.iter().any(|inst| matches!(inst, Some(Instruction::PushPc()))) {
+        return Vec::new();
+    }
+
+    let mut reached = vec![false; program.len()];
+    let mut seen = HashSet::new();
+    let mut pending = vec![(entry, Frame::Top)];
+    while let Some((addr, frame)) = pending.pop() {
+        if addr >= program.len() || !seen.insert((addr, frame)) {
+            continue;
+        }
+        reached[addr] = true;
+
+        // Undecodable words stop the run, so nothing follows them
+        let Some(instruction) = &decoded[addr] else {
+            continue;
+        };
+        match (instruction, frame) {
+            (Instruction::Return(bytes), Frame::Call(Some(depth))) if depth == (bytes >> 2) as i32 => (),
+            (Instruction::Return(_), Frame::Call(None)) => (),
+            (Instruction::Return(_), _) => return Vec::new(),
+            (Instruction::Call(_), _) => {
+                pending.extend(instruction.branch_target(addr).map(|target| (target, Frame::Call(Some(0)))));
+                pending.push((addr + 1, frame));
+            }
+            (&Instruction::SetTrapHandler(_, handler), _) => {
+                // A handler starts with the trap number above its return address
+                pending.push((handler as usize, Frame::Call(Some(1))));
+                pending.push((addr + 1, frame));
+            }
+            _ => {
+                let frame = frame.after(instruction);
+                pending.extend(instruction.successors(addr).into_iter().map(|next| (next, frame)));
+            }
+        }
+    }
+
+    (0..program.len()).filter(|&addr| !reached[addr]).collect()
+}
+
+// Where find_dead_code is in the program: outside any subroutine, or inside
+// one with the number of words above its return address when that is known.
+// A Return is only taken to go back to its Call when it is inside a
+// subroutine and, if the depth is known, pops exactly the words above the
+// return address
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Frame {
+    Top,
+    Call(Option<i32>),
+}
+
+impl Frame {
+    // The frame after `instruction` runs. Only simple stack effects are
+    // followed; anything else leaves the depth unknown
+    fn after(self, instruction: &Instruction) -> Frame {
+        use Instruction::*;
+        let Frame::Call(Some(depth)) = self else {
+            return self;
+        };
+        let change = match *instruction {
+            Push(_) | Dup(_) => 1,
+            Pop(bytes) => -((bytes >> 2) as i32),
+            Add() | Subtract() | Multiply() | Divide() | Remainder() | And() | Or() | Xor()
+            | LogicalLeftShift() | LogicalRightShift() | ArithmeticRightShift() => -1,
+            Negate() | Not() | Nop() | Swap(_, _) | Print(_, _) | Goto(_) | EqZero(_) | NeZero(_)
+            | LtZero(_) | GeZero(_) | IfOdd(_) | IfEven(_) => 0,
+            _ => return Frame::Call(None),
+        };
+        match depth + change {
+            depth @ 0..=1024 => Frame::Call(Some(depth)),
+            _ => Frame::Call(None),
+        }
+    }
+}
+
+// Check, without running anything, that every branch, call and trap handler
+// in a program file lands on an instruction of its code (not the constant
+// pool, data or the stack) and that byte-scaled branch offsets are whole
+// words. All problems are reported together, one line per instruction.
+pub fn verify_branches(program: &[u32]) -> Result<(), VmError> {
+    let (words, pool_len) = split_header(program)?;
+    let code = &words[..words.len() - pool_len];
+
+    let mut problems = Vec::new();
+    for (addr, &word) in code.iter().enumerate() {
+        let Ok(instruction) = Instruction::decode(word) else {
+            continue;
+        };
+
+        let target = match instruction {
+            Instruction::SetTrapHandler(_, 0xFFFF) => None,
+            Instruction::SetTrapHandler(_, handler) => Some(handler as i32),
+            _ => instruction.branch_offset().map(|words| addr as i32 + words),
+        };
+        if let Some(target) = target.filter(|dest| !(0..code.len() as i32).contains(dest)) {
+            problems.push(format!("    {:04x}: {:<24} target {} is outside the code (0-{})",
+                addr, instruction.to_string(), target, code.len() as i32 - 1));
+        }
+
+        // Call, Goto and the ifs encode their offset in bytes; the low two bits are dropped
+        if matches!(word >> 28, 0x5 | 0x7..=0x9) && word & 0b11 != 0 {
+            problems.push(format!("    {:04x}: {:<24} offset is not a whole number of words",
+                addr, instruction.to_string()));
+        }
+    }
+
+    if problems.is_empty() {
+        return Ok(());
+    }
+    Err(format!("(verify) {} bad branch(es):\n{}", problems.len(), problems.join("\n")).into())
+}
+
+impl Instruction {
+
+    // Addresses that can run after this instruction at `addr`; a Return goes
+    // back to the word after its Call, which the Call already covers
+    fn successors(&self, addr: usize) -> Vec<usize> {
+        use Instruction::*;
+        let next = addr + 1;
+        let target = self.branch_target(addr);
+        match *self {
+            Exit(_) | ExitStack() | Return(_) | Reserved(_) => Vec::new(),
+            Goto(_) => target.into_iter().collect(),
+            SkipIfZero() | SkipIfNonZero() => vec![next, next + 1],
+            SetTrapHandler(_, handler) => vec![next, handler as usize],
+            _ => target.into_iter().chain([next]).collect(),
+        }
+    }
+
+    // Address a branch or call at `addr` can jump to
+    fn branch_target(&self, addr: usize) -> Option<usize> {
+        self.branch_offset().and_then(|words| usize::try_from(addr as i32 + words).ok())
+    }
+
+    // Branch distance in instructions, for instructions that can jump
+    fn branch_offset(&self) -> Option<i32> {
+        use Instruction::*;
+        match *self {
+            Goto(words) | Call(words) | BinaryIf(_, words) | BitTest(_, words) => Some(words),
+            EqZero(bytes) | NeZero(bytes) | LtZero(bytes) | GeZero(bytes) | IfOdd(bytes) | IfEven(bytes)
+            | IfEmpty(bytes) | IfNotEmpty(bytes) => {
+                Some(bytes >> 2)
+            }
+            _ => None,
+        }
+    }
+
+    // Assembly mnemonic for the instruction
+    pub(crate) fn mnemonic(&self) -> &'static str {
+        use Instruction::*;
+        match self {
+            Exit(_) => "exit",
+            ExitStack() => "exitstack",
+            Swap(_, _) => "swap",
+            Nop() => "nop",
+            Input(_, _) => "input",
+            Stinput(_, _) => "stinput",
+            SlurpInput(_) => "slurp",
+            Syscall(_) => "syscall",
+            ClearStack() => "clear",
+            InputFailed() => "inputfailed",
+            InputEof() => "inputeof",
+            CyclesLeft() => "cyclesleft",
+            Capacity() => "capacity",
+            Free() => "free",
+            PushPc() => "pushpc",
+            PushSp() => "pushsp",
+            Argc() => "argc",
+            Argv() => "argv",
+            Rand() => "rand",
+            Assert() => "assert",
+            SetTrapHandler(_, _) => "settrap",
+            Flush() => "flush",
+            WaitKey() => "waitkey",
+            InputOrFlag() => "inputflag",
+            DumpLine() => "dumpline",
+            InputN(_) => "inputn",
+            Debug(_) => "debug",
+            Reserved(_) => "reserved",
+            Pop(_) => "pop",
+            Add() => "add",
+            Subtract() => "sub",
+            Multiply() => "mul",
+            Divide() => "div",
+            Remainder() => "rem",
+            And() => "and",
+            Or() => "or",
+            Xor() => "xor",
+            LogicalLeftShift() => "lsl",
+            LogicalRightShift() => "lsr",
+            ArithmeticRightShift() => "asr",
+            SatAdd() => "sadd",
+            SatSubtract() => "ssub",
+            SatMultiply() => "smul",
+            MulHigh() => "mulh",
+            MulHighU() => "mulhu",
+            Negate() => "neg",
+            Not() => "not",
+            Stprint(_, _) => "stprint",
+            Goto(_) => "goto",
+            Call(_) => "call",
+            Return(_) => "return",
+            BinaryIf(cond, _) => match cond {
+                0 => "ifeq",
+                1 => "ifne",
+                2 => "iflt",
+                3 => "ifgt",
+                4 => "ifle",
+                5 => "ifge",
+                _ => "if?",
+            },
+            EqZero(_) => "ifez",
+            NeZero(_) => "ifnz",
+            LtZero(_) => "ifmi",
+            GeZero(_) => "ifpl",
+            IfOdd(_) => "ifodd",
+            IfEven(_) => "ifeven",
+            IfEmpty(_) => "ifempty",
+            IfNotEmpty(_) => "ifnotempty",
+            Dup(_) => "dup",
+            DupN(_, _) => "dupn",
+            Add64() => "add64",
+            Sub64() => "sub64",
+            Mul64() => "mul64",
+            NumToStr() => "numtostr",
+            StrToNum() => "strtonum",
+            DupTop(_) => "duptop",
+            BitTest(_, _) => "ifbit",
+            RotLeft3() => "rotl3",
+            RotRight3() => "rotr3",
+            SkipIfZero() => "skipz",
+            PushConst(_) => "pushconst",
+            DupOp(_) => "dupop",
+            CMov() => "cmov",
+            Alloc(_) => "alloc",
+            HeapFree() => "dealloc",
+            SkipIfNonZero() => "skipnz",
+            Compare(cond, _) => match cond {
+                0 => "cmpeq",
+                1 => "cmpne",
+                2 => "cmplt",
+                3 => "cmpgt",
+                4 => "cmple",
+                5 => "cmpge",
+                _ => "cmp?",
+            },
+            EnterFrame() => "enter",
+            LeaveFrame() => "leave",
+            LoadFp(_) => "ldfp",
+            StoreFp(_) => "stfp",
+            LoadAbs(_) => "ldabs",
+            StoreAbs(_) => "stabs",
+            MemCopy() => "memcopy",
+            LoadByte(_, true) => "ldbyte",
+            LoadByte(_, false) => "ldbyteu",
+            Dump() => "dump",
+            Print(_, true) => "print",
+            Print(_, false) => "printn",
+            Push(_) => "push",
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use Instruction::*;
+        let name = self.mnemonic();
+        match *self {
+            // Swap fields are signed 12-bit word offsets
+            Swap(from, to) => write!(f, "{} {} {}", name, ((from << 4) >> 4) * 4, ((to << 4) >> 4) * 4),
+
+            Exit(code) => write!(f, "{} {}", name, code),
+            Input(lenient, radix) => {
+                write!(f, "{}", name)?;
+                match radix {
+                    10 => write!(f, " dec")?,
+                    16 => write!(f, " hex")?,
+                    2 => write!(f, " bin")?,
+                    _ => (),
+                }
+                if lenient {
+                    write!(f, " lenient")?;
+                }
+                Ok(())
+            }
+            SetTrapHandler(trap, 0xFFFF) => write!(f, "{} {} none", name, trap),
+            SetTrapHandler(trap, addr) => write!(f, "{} {} {:#06x}", name, trap, addr),
+            Stinput(max, false) => write!(f, "{} {:#x}", name, max),
+            Stinput(max, true) => write!(f, "{} {:#x} counted", name, max),
+            Reserved(word) => write!(f, "{} {:#010x}", name, word),
+            Syscall(num) | Debug(num) | SlurpInput(num) | DupTop(num) | PushConst(num) | Alloc(num)
+            | InputN(num) => {
+                write!(f, "{} {}", name, num)
+            }
+            Pop(bytes) | Return(bytes) | Dup(bytes) => write!(f, "{} {}", name, bytes),
+            Stprint(bytes, false) => write!(f, "{} {}", name, bytes),
+            Stprint(bytes, true) => write!(f, "{} {} counted", name, bytes),
+
+            // Goto/Call/BinaryIf offsets are in words; unary ifs are in bytes
+            Goto(words) | Call(words) | BinaryIf(_, words) => write!(f, "{} {:+}", name, words),
+            EqZero(bytes) | NeZero(bytes) | LtZero(bytes) | GeZero(bytes) | IfOdd(bytes) | IfEven(bytes)
+            | IfEmpty(bytes) | IfNotEmpty(bytes) => {
+                write!(f, "{} {:+}", name, bytes >> 2)
+            }
+
+            DupN(bytes, count) => write!(f, "{} {} {}", name, bytes, count),
+            BitTest(bit, words) => write!(f, "{} {} {:+}", name, bit, words),
+            Compare(_, true) => write!(f, "{} signed", name),
+            DupOp(func) => match Instruction::decode(0x2000_0000 | (func << 24)) {
+                Ok(op) => write!(f, "{} {}", name, op.mnemonic()),
+                Err(_) => write!(f, "{} ?", name),
+            },
+            Print(offset, _) => {
+                write!(f, "{} {}", name, offset & !0b11)?;
+                match offset & 0b11 {
+                    0b01 => write!(f, " hex"),
+                    0b10 => write!(f, " bin"),
+                    0b11 => write!(f, " oct"),
+                    _ => Ok(()),
+                }
+            }
+            LoadFp(bytes) | StoreFp(bytes) => write!(f, "{} {}", name, bytes),
+            LoadAbs(base) | StoreAbs(base) | LoadByte(base, _) => write!(f, "{} {}", name, base),
+            Push(value) => write!(f, "{} {}", name, value as i32),
+
+            _ => write!(f, "{}", name),
+        }
+    }
+}
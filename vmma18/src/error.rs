@@ -0,0 +1,51 @@
+/* Synopsis: Error type returned by every fallible machine operation
+ */
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+// Anything that stops a program: a fault raised while loading or executing
+// (bad encoding, stack overflow, out-of-bounds access, ...) or a failure of
+// the machine's input/output streams
+#[derive(Debug)]
+pub enum VmError {
+    Fault(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::Fault(message) => write!(f, "{}", message),
+            VmError::Io(e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for VmError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            VmError::Io(e) => Some(e),
+            VmError::Fault(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for VmError {
+    fn from(e: io::Error) -> VmError {
+        VmError::Io(e)
+    }
+}
+
+impl From<&str> for VmError {
+    fn from(message: &str) -> VmError {
+        VmError::Fault(message.to_string())
+    }
+}
+
+impl From<String> for VmError {
+    fn from(message: String) -> VmError {
+        VmError::Fault(message)
+    }
+}
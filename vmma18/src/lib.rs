@@ -0,0 +1,2672 @@
+/* Names: Tyler Cartier, Thomas Latawic, Ryan Perry, Chase Woodfill
+ * Date: 05/06/2025
+ * Synopsis: Virtual machine that executes 4-byte instructions in an assembled binary file
+ */
+
+use std::collections::VecDeque;
+use std::io::{Cursor, Read, Write};
+
+mod bintrace;
+mod difftest;
+mod disasm;
+mod error;
+#[cfg(test)]
+mod tests;
+
+pub use bintrace::{binary_trace_header, parse_binary_trace, BinaryTraceRecord};
+pub use difftest::{parse_trace, TraceStep};
+pub use disasm::{disassemble, disassemble_raw, find_dead_code, verify_branches};
+pub use error::VmError;
+
+// Virtual Machine structure, parameterized over input/output types (for testing flexibility)
+// and over the host environment that services Syscall instructions
+pub struct Machine<R: Read, W: Write, H: HostEnv = NullHost> {
+    ram: [u32; 1024], 
+    sp: i16,         
+    pc: i16,         
+    input: PeekableReader<R>,
+    output: CountingWriter<W>,
+    host: H,
+    pub frame_check: FrameCheck,
+    frames: Vec<(u32, i16)>,
+    pub direction: StackDirection,
+    stack_bottom: i16,
+    fp: i16,
+    pub max_cycles: Option<u64>,
+    cycles: u64,
+    program_len: usize,
+    pub guard_stack: bool,
+    pub trace: Option<TraceFormat>,
+    pub entry: usize,
+    input_failed: bool,
+    pub formatter: Box<dyn PrintFormatter>,
+    rng: u64,
+    pub flush: FlushPolicy,
+    pub trace_output: Option<Box<dyn Write>>,
+    trace_started: bool,
+    pub word_width: WordWidth,
+    stats: RunStats,
+    pub strict_branches: bool,
+    pub max_output: Option<u64>,
+    traps: [Option<i16>; TRAP_KINDS],
+    pub echo_input: bool,
+    call_depth: u64,
+    pub trace_sample: Option<u64>,
+    hook: Option<InstructionHook<R, W, H>>,
+    args: Vec<Vec<u8>>,
+    pub quiet: bool,
+    pool_base: usize,
+    pool_len: usize,
+    heap_base: usize,
+    heap_top: usize,
+    pub color: bool,
+    pub trap_null_write: bool,
+    pub interactive: bool,
+    pub initial_sp: Option<i16>,
+    recent: VecDeque<(i16, u32)>,
+}
+
+// Embedder callback run before each instruction executes, see Machine::set_hook
+pub type InstructionHook<R, W, H> = Box<dyn FnMut(&Machine<R, W, H>, &Instruction)>;
+
+// Counters describing a run, for --stats
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct RunStats {
+    pub cycles: u64,
+    pub peak_depth: i16,
+    pub calls: u64,
+    pub returns: u64,
+    pub peak_call_depth: u64,
+    pub output_bytes: u64,
+}
+
+// One live stack word, preformatted for display
+#[derive(Debug, Clone, PartialEq)]
+pub struct StackEntry {
+    pub address: usize,
+    pub value: u32,
+    pub as_signed: i32,
+    pub as_hex: String,
+}
+
+// Program output stream that counts the bytes written through it
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: u64,
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.count += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// First word of a program file: version 1, or a version word follows
+const MAGIC: u32 = 0xEFBE_ADDE;
+const VERSIONED_MAGIC: u32 = 0xEFBE_ADDF;
+
+// Arithmetic traps a program can handle itself
+const TRAP_DIVIDE_BY_ZERO: usize = 0;
+const TRAP_DIVIDE_OVERFLOW: usize = 1;
+const TRAP_KINDS: usize = 2;
+
+// Set in a heap block's header word while the block is allocated
+const HEAP_LIVE: u32 = 0x8000_0000;
+
+// Instructions kept for the context of an error
+const RECENT_INSTRUCTIONS: usize = 8;
+
+// Program input stream that can look at its next byte without consuming it
+struct PeekableReader<R: Read> {
+    inner: R,
+    peeked: Option<u8>,
+}
+
+impl<R: Read> PeekableReader<R> {
+
+    // Whether every byte has been read; blocks until one arrives on a terminal
+    fn at_eof(&mut self) -> std::io::Result<bool> {
+        if self.peeked.is_none() {
+            let mut byte = [0; 1];
+            if self.inner.read(&mut byte)? > 0 {
+                self.peeked = Some(byte[0]);
+            }
+        }
+        Ok(self.peeked.is_none())
+    }
+}
+
+impl<R: Read> Read for PeekableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.peeked.take() {
+            Some(byte) if !buf.is_empty() => {
+                buf[0] = byte;
+                Ok(1)
+            }
+            peeked => {
+                self.peeked = peeked;
+                self.inner.read(buf)
+            }
+        }
+    }
+}
+
+// Seed used by Rand until the embedder picks another, so runs are reproducible
+const DEFAULT_SEED: u64 = 0x5EED;
+
+// Format of the per-instruction trace, written to `Machine::trace_output`
+// (stderr when that is not set)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    // One aligned line per instruction, for reading
+    Human,
+    // One JSON object per instruction with pc, sp, opcode, mnemonic and
+    // (when the stack is not empty) stack_top
+    Jsonl,
+    // Fixed-size records after a header, for long runs (see bintrace.rs)
+    Binary,
+}
+
+// Which way the stack grows through RAM.
+//
+// SP always addresses the top word. A downward stack starts empty at SP = 1024
+// (the ISA default); an upward stack starts just past the loaded program and
+// grows toward the end of RAM. SP-relative offsets count from the top toward
+// the bottom of the stack in either direction.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum StackDirection {
+    #[default]
+    Down,
+    Up,
+}
+
+// Width of the values arithmetic works on.
+//
+// RAM words are always 32 bits, so code and packed strings are unaffected. In
+// 16-bit mode arithmetic, comparisons, Input and Print read each operand as a
+// sign-extended 16-bit value and results wrap (or saturate) at 16 bits. The
+// 64-bit pair instructions still combine full 32-bit words.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum WordWidth {
+    #[default]
+    Bits32,
+    Bits16,
+}
+
+// When output written by Print, Stprint, Dump and Debug is flushed
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FlushPolicy {
+    // After every output instruction
+    #[default]
+    Always,
+    // After output instructions that write a newline
+    Line,
+    // Only when the program exits or the run stops
+    OnExit,
+}
+
+// How to react when a subroutine returns with the stack unbalanced
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum FrameCheck {
+    #[default]
+    Off,
+    Warn,
+    Strict,
+}
+
+// Everything needed to resume a program: RAM, registers, the stack layout and
+// the run's progress (cycles, input status, call frames, word width, the rand
+// generator and the trap handlers).
+//
+// The raw form (to_bytes/from_bytes) is a sequence of little-endian words:
+// the "VMST" tag, SP, PC, stack direction (0 = down, 1 = up), the empty-stack
+// SP, the program length, the frame pointer, the constant pool's base and
+// length, the heap's base and top, the cycle count (low word first), the
+// input-failed flag, the call depth (low word first), the word width
+// (0 = 32 bits, 1 = 16 bits), the rand generator's state (low word first),
+// each trap's handler address (0xFFFF for none), the number of call frames,
+// then all of RAM, then each call frame as its return address and SP.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MachineState {
+    pub ram: Vec<u32>,
+    pub sp: i16,
+    pub pc: i16,
+    pub direction: StackDirection,
+    pub stack_bottom: i16,
+    pub program_len: usize,
+    pub fp: i16,
+    pub pool_base: usize,
+    pub pool_len: usize,
+    pub heap_base: usize,
+    pub heap_top: usize,
+    pub cycles: u64,
+    pub input_failed: bool,
+    pub call_depth: u64,
+    pub word_width: WordWidth,
+    pub rng: u64,
+    pub traps: [Option<i16>; TRAP_KINDS],
+    pub frames: Vec<(u32, i16)>,
+}
+
+const STATE_TAG: u32 = u32::from_le_bytes(*b"VMST");
+const STATE_HEADER_WORDS: usize = 22;
+
+impl MachineState {
+
+    // Serialize the state to its raw form
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let direction = match self.direction {
+            StackDirection::Down => 0,
+            StackDirection::Up => 1,
+        };
+        let width = match self.word_width {
+            WordWidth::Bits32 => 0,
+            WordWidth::Bits16 => 1,
+        };
+        let header = [
+            STATE_TAG,
+            self.sp as u32,
+            self.pc as u32,
+            direction,
+            self.stack_bottom as u32,
+            self.program_len as u32,
+            self.fp as u32,
+            self.pool_base as u32,
+            self.pool_len as u32,
+            self.heap_base as u32,
+            self.heap_top as u32,
+            self.cycles as u32,
+            (self.cycles >> 32) as u32,
+            self.input_failed as u32,
+            self.call_depth as u32,
+            (self.call_depth >> 32) as u32,
+            width,
+            self.rng as u32,
+            (self.rng >> 32) as u32,
+            self.traps[TRAP_DIVIDE_BY_ZERO].map_or(0xFFFF, |addr| addr as u32),
+            self.traps[TRAP_DIVIDE_OVERFLOW].map_or(0xFFFF, |addr| addr as u32),
+            self.frames.len() as u32,
+        ];
+        let frames = self.frames.iter().flat_map(|&(return_address, sp)| [return_address, sp as u32]);
+
+        header.iter().chain(&self.ram).copied().chain(frames).flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    // Parse a state previously written by to_bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<MachineState, VmError> {
+        if !bytes.len().is_multiple_of(4) {
+            return Err("machine state is not a whole number of words".into());
+        }
+        let words: Vec<u32> = bytes
+            .chunks_exact(4)
+            .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect();
+
+        if words.first() != Some(&STATE_TAG) {
+            return Err("not a machine state file".into());
+        }
+        let frames_start = STATE_HEADER_WORDS + 1024;
+        if words.len() < frames_start {
+            return Err("machine state has the wrong RAM size".into());
+        }
+        if words.len() - frames_start != 2 * words[21] as usize {
+            return Err("machine state has the wrong number of call frames".into());
+        }
+
+        let direction = match words[3] {
+            0 => StackDirection::Down,
+            1 => StackDirection::Up,
+            _ => return Err("machine state has an unknown stack direction".into()),
+        };
+        let word_width = match words[16] {
+            0 => WordWidth::Bits32,
+            1 => WordWidth::Bits16,
+            _ => return Err("machine state has an unknown word width".into()),
+        };
+
+        // SP must be between the empty-stack SP and the far end of RAM
+        let (sp, stack_bottom) = (words[1] as i32, words[4] as i32);
+        let stack_ok = match direction {
+            StackDirection::Down => 0 <= sp && sp <= stack_bottom && stack_bottom <= 1024,
+            StackDirection::Up => -1 <= stack_bottom && stack_bottom <= sp && sp < 1024,
+        };
+        if !stack_ok {
+            return Err("machine state has a stack pointer outside the stack".into());
+        }
+
+        // The pool is the end of the program, which must fit in RAM
+        let (program_len, pool_base, pool_len) = (words[5] as usize, words[7] as usize, words[8] as usize);
+        if program_len > 1024 || pool_base + pool_len > program_len {
+            return Err("machine state has a constant pool outside the program".into());
+        }
+
+        // The heap lies between the program and the end of RAM
+        let (heap_base, heap_top) = (words[9] as usize, words[10] as usize);
+        if heap_base > heap_top || heap_top > 1024 {
+            return Err("machine state has a heap outside RAM".into());
+        }
+
+        let mut traps = [None; TRAP_KINDS];
+        for (handler, &addr) in traps.iter_mut().zip(&words[19..21]) {
+            *handler = match addr {
+                0xFFFF => None,
+                0..1024 => Some(addr as i16),
+                _ => return Err("machine state has a trap handler outside RAM".into()),
+            };
+        }
+
+        Ok(MachineState {
+            ram: words[STATE_HEADER_WORDS..frames_start].to_vec(),
+            sp: sp as i16,
+            pc: words[2] as i16,
+            direction,
+            stack_bottom: stack_bottom as i16,
+            program_len,
+            fp: words[6] as i16,
+            pool_base,
+            pool_len,
+            heap_base,
+            heap_top,
+            cycles: words[11] as u64 | (words[12] as u64) << 32,
+            input_failed: words[13] != 0,
+            call_depth: words[14] as u64 | (words[15] as u64) << 32,
+            word_width,
+            rng: words[17] as u64 | (words[18] as u64) << 32,
+            traps,
+            frames: words[frames_start..].chunks_exact(2).map(|frame| (frame[0], frame[1] as i16)).collect(),
+        })
+    }
+}
+
+// Host integration point for the Syscall instruction.
+//
+// The embedder receives the syscall number (the instruction's 24-bit immediate),
+// the whole of RAM, and the stack pointer, so a host call can pop arguments and
+// push results the same way an instruction would.
+pub trait HostEnv {
+    fn syscall(&mut self, num: u32, stack: &mut [u32], sp: &mut i16)
+        -> Result<(), VmError>;
+}
+
+// Default host: no syscalls are available
+pub struct NullHost;
+
+impl HostEnv for NullHost {
+    fn syscall(&mut self, num: u32, _stack: &mut [u32], _sp: &mut i16)
+        -> Result<(), VmError> {
+        Err(format!("(syscall) no host handler for syscall {}", num).into())
+    }
+}
+
+// Renders the value a Print instruction shows.
+//
+// `radix` is the base selected by the instruction's low two bits: 10, 16, 2
+// or 8. Install a custom formatter on `Machine::formatter` to change how
+// numbers look without adding opcodes.
+pub trait PrintFormatter {
+    fn format(&self, value: u32, radix: u32) -> String;
+}
+
+// Default formatting: signed decimal, or a prefixed hex/binary/octal value
+pub struct DefaultFormatter;
+
+impl PrintFormatter for DefaultFormatter {
+    fn format(&self, value: u32, radix: u32) -> String {
+        let value = value as i32;
+        match radix {
+            16 => format!("{:#x}", value),
+            2 => format!("{:#b}", value),
+            8 => format!("{:#o}", value),
+            _ => format!("{}", value),
+        }
+    }
+}
+
+// Instruction set (interpreted from RAM contents)
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Instruction {
+    // Miscellaneous
+    Exit(u8),        
+    ExitStack(),
+    Swap(i16, i16),   
+    Nop(),           
+    Input(bool, u32),
+    Stinput(u32, bool),
+    SlurpInput(u32),
+    Syscall(u32),
+    ClearStack(),
+    InputFailed(),
+    InputEof(),
+    CyclesLeft(),
+    Capacity(),
+    Free(),
+    PushPc(),
+    PushSp(),
+    Argc(),
+    Argv(),
+    Rand(),
+    Assert(),
+    SetTrapHandler(u32, u32),
+    Flush(),
+    WaitKey(),
+    InputOrFlag(),
+    DumpLine(),
+    InputN(u32),
+    Debug(u32),
+    Reserved(u32),       
+
+    Pop(u32),         
+
+    // Binary Arithmetic
+    Add(),
+    Subtract(),
+    Multiply(),
+    Divide(),
+    Remainder(),
+    And(),
+    Or(),
+    Xor(),
+    LogicalLeftShift(),
+    LogicalRightShift(),
+    ArithmeticRightShift(),
+    SatAdd(),
+    SatSubtract(),
+    SatMultiply(),
+    MulHigh(),
+    MulHighU(),
+
+    // Unary Arithmetic
+    Negate(),
+    Not(),
+
+    Stprint(i32, bool),
+
+    Goto(i32),
+
+    Call(i32),
+    Return(u32),
+
+    BinaryIf(u8, i32),
+
+    // Unary If
+    EqZero(i32),      
+    NeZero(i32),      
+    LtZero(i32),      
+    GeZero(i32),     
+    IfOdd(i32),
+    IfEven(i32),
+    IfEmpty(i32),
+    IfNotEmpty(i32),
+    Dup(u32),
+
+    // Extended
+    DupN(u32, u32),
+    Add64(),
+    Sub64(),
+    Mul64(),
+    NumToStr(),
+    StrToNum(),
+    DupTop(u32),
+    BitTest(u32, i32),
+    RotLeft3(),
+    RotRight3(),
+    Compare(u8, bool),
+    SkipIfZero(),
+    SkipIfNonZero(),
+
+    // Memory and frames
+    EnterFrame(),
+    LeaveFrame(),
+    LoadFp(i32),
+    StoreFp(i32),
+    LoadAbs(u32),
+    StoreAbs(u32),
+    MemCopy(),
+    LoadByte(u32, bool),
+    Alloc(u32),
+    HeapFree(),
+    PushConst(u32),
+    DupOp(u32),
+    CMov(),
+
+    Dump(),
+    Print(i32, bool),
+
+    Push(u32),        
+}
+
+// Top-level instruction class based on opcode nibble
+#[derive(Debug)]
+enum Opcode {
+    Miscellaneous, 
+    BinaryArithmetic,
+    UnaryArithmetic,
+    Pop,          
+    StringPrint,   
+    Goto,
+    Call,
+    Return,
+    UnaryIf,     
+    BinaryIf,
+    Dup,
+    Extended,
+    Memory,
+    Print,
+    Dump,
+    Push,          
+    Unknown,       
+}
+
+impl Opcode {
+
+    // Convert the top 4 bits (opcode) of an instruction to an `Opcode` enum
+    fn from_integer(n: u8) -> Opcode {
+        match n {
+            0x0 => Opcode::Miscellaneous,
+            0x1 => Opcode::Pop,
+            0x2 => Opcode::BinaryArithmetic,
+            0x3 => Opcode::UnaryArithmetic,
+            0x4 => Opcode::StringPrint,
+            0x5 => Opcode::Call,
+            0x6 => Opcode::Return,
+            0x7 => Opcode::Goto,
+            0x8 => Opcode::BinaryIf,
+            0x9 => Opcode::UnaryIf,
+            0xA => Opcode::Extended,
+            0xB => Opcode::Memory,
+            0xC => Opcode::Dup,
+            0xD => Opcode::Print,
+            0xE => Opcode::Dump,
+            0xF => Opcode::Push,
+            _ => Opcode::Unknown,
+        }
+    }
+}
+
+impl Instruction {
+
+    // Decode a single instruction word
+    fn decode(inst: u32) -> Result<Instruction, VmError> {
+        let opcode = Opcode::from_integer(((inst >> 28) & 0xF) as u8);
+
+        use Instruction::*;
+        Ok(match opcode {
+            Opcode::Miscellaneous => match (inst >> 24) & 0xF {
+                // Exit code in the low byte
+                0x0 => Exit((inst & 0xFF) as u8),
+
+                // Raw 12-bit fields (bits 12-23 and 0-11), sign-extended when executed
+                0x1 => Swap(((inst >> 12) & 0xFFF) as i16, (inst & 0xFFF) as i16),
+                0x2 => Nop(),
+
+                // Machine queries, selected by the low byte
+                0x3 => match inst & 0xFF {
+                    0x0 => InputFailed(),
+                    0x1 => CyclesLeft(),
+                    0x2 => Capacity(),
+                    0x3 => Free(),
+                    0x4 => PushPc(),
+                    0x5 => PushSp(),
+                    0x6 => Argc(),
+                    0x7 => Argv(),
+                    0x8 => InputEof(),
+
+                    // Unassigned selectors are kept for future instructions
+                    _ => Reserved(inst),
+                },
+
+                // Bit 0 set: a bad number pushes 0 instead of stopping the program.
+                // Bits 1-2 restrict the base: any (0), decimal, hex or binary
+                0x4 => Input((inst & 1) != 0, [0, 10, 16, 2][((inst >> 1) & 0b11) as usize]),
+
+                // Maximum length in bits 0-22; bit 23 selects a length-prefixed string
+                0x5 => Stinput(inst & 0x7FFFFF, (inst >> 23) & 1 == 1),
+                0x6 => Syscall(inst & 0xFFFFFF),
+
+                // Print without a trailing newline, taking a signed 24-bit offset
+                0x7 => Print(((inst << 8) as i32) >> 8, false),
+                0x8 => ClearStack(),
+                0x9 => SlurpInput(inst & 0xFFFFFF),
+                0xA => Rand(),
+                0xB => Assert(),
+
+                // Trap number in bits 16-23, handler word address in bits 0-15
+                0xC => SetTrapHandler((inst >> 16) & 0xFF, inst & 0xFFFF),
+                0xD => Flush(),
+
+                // Interactive input and output, and a computed exit, selected by the low byte
+                0xE => match inst & 0xFF {
+                    0x0 => WaitKey(),
+                    0x1 => InputOrFlag(),
+                    0x2 => DumpLine(),
+
+                    // Number count in bits 8-23
+                    0x3 => InputN((inst >> 8) & 0xFFFF),
+
+                    // Exit code taken from the stack
+                    0x4 => ExitStack(),
+                    _ => Reserved(inst),
+                },
+                0xF => Debug(inst & 0xFFFFFF),
+                _ => return Err("Invalid Miscellaneous Instruction".into()),
+            },
+
+            Opcode::BinaryArithmetic => match (inst >> 24) & 0xF {
+                0x0 => Add(),
+                0x1 => Subtract(),
+                0x2 => Multiply(),
+                0x3 => Divide(),
+                0x4 => Remainder(),
+                0x5 => And(),
+                0x6 => Or(),
+                0x7 => Xor(),
+                0x8 => LogicalLeftShift(),
+                0x9 => LogicalRightShift(),
+                0xB => ArithmeticRightShift(),
+
+                // Saturating variants clamp to i32::MIN/MAX instead of wrapping
+                0xA => SatAdd(),
+                0xC => SatSubtract(),
+                0xD => SatMultiply(),
+
+                // High 32 bits of the full 64-bit product, signed and unsigned
+                0xE => MulHigh(),
+                0xF => MulHighU(),
+                _ => return Err("Invalid Binary Arithmetic Instruction".into()),
+            },
+
+
+            Opcode::UnaryArithmetic => match (inst >> 24) & 0xF {
+                0x0 => Negate(),
+                0x1 => Not(),
+                _ => return Err("Invalid Unary Arithmetic Instruction".into()),
+            },
+
+            Opcode::Pop => Pop(inst & 0x0FFF_FFFF),
+
+            Opcode::Goto => {
+                // Extract offset
+                let raw = (inst >> 2) & 0x03FF_FFFF;
+
+                let offset = if (raw & (1 << 25)) != 0 {
+                    // Sign extend negative offset
+                    (raw | !0x03FF_FFFF) as i32
+                } else {
+                    raw as i32
+                };
+                Goto(offset)
+            },
+
+            // Signed 28-bit byte offset from SP; negative reaches words above the top of the stack.
+            // The offset's unused bit 0 selects a length-prefixed string
+            Opcode::StringPrint => Stprint((((inst << 4) as i32) >> 4) & !0b11, inst & 1 == 1),
+
+            Opcode::Call => {
+                // Extract offset
+                let raw = (inst >> 2) & 0x03FF_FFFF;
+
+                let offset = if (raw & (1 << 25)) != 0 {
+                    // Sign extend negative offset
+                    (raw | !0x03FF_FFFF) as i32
+                } else {
+                    raw as i32
+                };
+                Call(offset)
+            },
+            Opcode::Return => {
+                let offset = inst & 0x0FFF_FFFF;
+                Return(offset)
+            }
+
+            Opcode::BinaryIf => {
+                let cond = (inst >> 25) & 0b111;
+                let raw = (inst >> 2) & 0x007F_FFFF;
+                let offset = if (raw & (1 << 22)) != 0 {
+                    (raw as i32) | !0x007F_FFFF
+                } else {
+                    raw as i32
+                };
+                BinaryIf(cond as u8, offset)
+            }
+
+            Opcode::UnaryIf => {
+
+                // Branching instruction (e.g. EqZero, NeZero, etc.); bit 27 selects the parity and stack tests
+                let func2 = (inst >> 25) & 0b111;
+                let offset = {
+                    let mut val = (inst & 0x00FF_FFFF) as i32;
+                    if (val >> 23) == 1 {
+
+                        // Sign extend negative values
+                        val |= 0xFF00_0000u32 as i32;
+                    }
+                    val
+                };
+                match func2 {
+                    0b00 => EqZero(offset),
+                    0b01 => NeZero(offset),
+                    0b10 => LtZero(offset),
+                    0b11 => GeZero(offset),
+                    0b100 => IfOdd(offset),
+                    0b101 => IfEven(offset),
+                    0b110 => IfEmpty(offset),
+                    0b111 => IfNotEmpty(offset),
+                    _ => unreachable!(),
+                }
+            }
+            Opcode::Dup => {
+                let offset = inst & 0x0FFF_FFFF;
+                Dup(offset) 
+            }
+
+            // Extended instructions: sub-opcode in bits 24-27
+            Opcode::Extended => match (inst >> 24) & 0xF {
+
+                // Byte offset from SP in bits 12-23, word count in bits 0-11
+                0x0 => DupN((inst >> 12) & 0xFFF, inst & 0xFFF),
+
+                // 64-bit arithmetic on word pairs, function in bits 0-3
+                0x1 => match inst & 0xF {
+                    0x0 => Add64(),
+                    0x1 => Sub64(),
+                    0x2 => Mul64(),
+                    _ => return Err("Invalid 64-bit Arithmetic Instruction".into()),
+                },
+                0x2 => NumToStr(),
+                0x3 => StrToNum(),
+
+                // Number of extra copies in bits 0-23
+                0x4 => DupTop(inst & 0xFFFFFF),
+
+                // Bit index in bits 19-23, signed word offset in bits 0-18
+                0x5 => BitTest((inst >> 19) & 0x1F, ((inst << 13) as i32) >> 13),
+
+                // Three-word rotations, direction in bit 0
+                0x6 => match inst & 0xF {
+                    0x0 => RotLeft3(),
+                    0x1 => RotRight3(),
+                    _ => return Err("Invalid Rotate Instruction".into()),
+                },
+
+                // BinaryIf condition in bits 0-3, signed comparison when bit 4 is set
+                0x7 => match inst & 0xF {
+                    cond @ 0x0..=0x5 => Compare(cond as u8, (inst >> 4) & 1 == 1),
+                    _ => return Err("Invalid Compare Instruction".into()),
+                },
+
+                // Predicated skips, condition in bit 0
+                0x8 => match inst & 0xF {
+                    0x0 => SkipIfZero(),
+                    0x1 => SkipIfNonZero(),
+                    _ => return Err("Invalid Skip Instruction".into()),
+                },
+
+                // Constant pool index in bits 0-23
+                0x9 => PushConst(inst & 0xFFFFFF),
+
+                // Binary arithmetic function (as in bits 24-27 of a 0x2 word) in bits 0-3
+                0xA => DupOp(inst & 0xF),
+                0xB => CMov(),
+                _ => return Err("Invalid Extended Instruction".into()),
+            },
+
+            Opcode::Memory => match (inst >> 24) & 0xF {
+                0x0 => EnterFrame(),
+                0x1 => LeaveFrame(),
+
+                // Signed 24-bit byte offset from FP; positive is deeper in the stack
+                0x2 => LoadFp(((inst << 8) as i32) >> 8),
+                0x3 => StoreFp(((inst << 8) as i32) >> 8),
+
+                // Base word address in bits 0-23; the index comes from the stack
+                0x4 => LoadAbs(inst & 0xFFFFFF),
+                0x5 => StoreAbs(inst & 0xFFFFFF),
+                0x6 => MemCopy(),
+
+                // Base byte address in bits 0-23, byte index from the stack; 0x8 zero-extends
+                0x7 => LoadByte(inst & 0xFFFFFF, true),
+                0x8 => LoadByte(inst & 0xFFFFFF, false),
+
+                // Heap blocks: size in words in bits 0-23; free takes the address from the stack
+                0x9 => Alloc(inst & 0xFFFFFF),
+                0xA => HeapFree(),
+                _ => return Err("Invalid Memory Instruction".into()),
+            },
+
+            Opcode::Print => Print((inst & 0x0FFF_FFFF) as i32, true),
+            Opcode::Dump => Dump(),
+            Opcode::Push => {
+
+                // Push a signed immediate value
+                let mut val = inst & 0x0FFF_FFFF;
+                if (val >> 27) == 1 {
+                    val |= 0xF000_0000;
+                }
+                Push(val)
+            }
+
+            _ => return Err("Unimplemented opcode".into()),
+        })
+    }
+}
+
+impl<W: Write> Machine<Cursor<Vec<u8>>, W, NullHost> {
+
+    // Create a machine whose input is the given lines, each ended by a newline,
+    // so Input and Stinput can be driven without a real input stream
+    pub fn with_input_lines(lines: &[&str], output: W) -> Machine<Cursor<Vec<u8>>, W, NullHost> {
+        let mut input = Vec::new();
+        for line in lines {
+            input.extend_from_slice(line.as_bytes());
+            input.push(b'\n');
+        }
+        Machine::new(Cursor::new(input), output, NullHost)
+    }
+}
+
+impl<R: Read, W: Write, H: HostEnv> Machine<R, W, H> {
+
+    // Create a machine with empty RAM
+    pub fn new(input: R, output: W, host: H) -> Machine<R, W, H> {
+        Machine {
+            ram: [0; 1024],
+            sp: 1024,
+            pc: 0,
+            input: PeekableReader { inner: input, peeked: None },
+            output: CountingWriter { inner: output, count: 0 },
+            host,
+            frame_check: FrameCheck::Off,
+            frames: Vec::new(),
+            direction: StackDirection::Down,
+            stack_bottom: 1024,
+            fp: 1024,
+            max_cycles: None,
+            cycles: 0,
+            program_len: 0,
+            guard_stack: false,
+            trace: None,
+            entry: 0,
+            input_failed: false,
+            formatter: Box::new(DefaultFormatter),
+            rng: DEFAULT_SEED,
+            flush: FlushPolicy::Always,
+            trace_output: None,
+            trace_started: false,
+            word_width: WordWidth::Bits32,
+            stats: RunStats::default(),
+            strict_branches: false,
+            max_output: None,
+            traps: [None; TRAP_KINDS],
+            echo_input: false,
+            call_depth: 0,
+            trace_sample: None,
+            hook: None,
+            args: Vec::new(),
+            quiet: false,
+            pool_base: 0,
+            pool_len: 0,
+            heap_base: 0,
+            heap_top: 0,
+            color: false,
+            trap_null_write: false,
+            interactive: false,
+            initial_sp: None,
+            recent: VecDeque::with_capacity(RECENT_INSTRUCTIONS),
+        }
+    }
+
+    // Rebuild a machine from a captured state, ready to continue running
+    pub fn from_state(state: MachineState, input: R, output: W, host: H) -> Machine<R, W, H> {
+        let mut machine = Machine::new(input, output, host);
+
+        let len = state.ram.len().min(machine.ram.len());
+        machine.ram[..len].copy_from_slice(&state.ram[..len]);
+        machine.sp = state.sp;
+        machine.pc = state.pc;
+        machine.direction = state.direction;
+        machine.stack_bottom = state.stack_bottom;
+        machine.fp = state.fp;
+        machine.program_len = state.program_len;
+        machine.pool_base = state.pool_base;
+        machine.pool_len = state.pool_len;
+        machine.heap_base = state.heap_base;
+        machine.heap_top = state.heap_top;
+        machine.cycles = state.cycles;
+        machine.input_failed = state.input_failed;
+        machine.call_depth = state.call_depth;
+        machine.word_width = state.word_width;
+        machine.seed_rng(state.rng);
+        machine.traps = state.traps;
+        machine.frames = state.frames;
+        machine
+    }
+
+    // Capture the state needed to resume this machine later
+    pub fn snapshot(&self) -> MachineState {
+        MachineState {
+            ram: self.ram.to_vec(),
+            sp: self.sp,
+            pc: self.pc,
+            direction: self.direction,
+            stack_bottom: self.stack_bottom,
+            fp: self.fp,
+            program_len: self.program_len,
+            pool_base: self.pool_base,
+            pool_len: self.pool_len,
+            heap_base: self.heap_base,
+            heap_top: self.heap_top,
+            cycles: self.cycles,
+            input_failed: self.input_failed,
+            call_depth: self.call_depth,
+            word_width: self.word_width,
+            rng: self.rng,
+            traps: self.traps,
+            frames: self.frames.clone(),
+        }
+    }
+
+    // Load a program into RAM, checking for magic number
+    pub fn load(&mut self, program: &[u32]) -> Result<(), VmError> {
+        let (code, pool_len) = split_header(program)?;
+
+        if code.len() > self.ram.len() {
+            return Err("Program too large".into());
+        }
+
+        // A header alone is rejected rather than running the zeroed RAM as `exit 0`
+        if code.is_empty() {
+            return Err("empty program".into());
+        }
+
+        // Execution may start past a header or data block, but not past the program
+        if self.entry != 0 && self.entry >= code.len() {
+            return Err("Entry point is outside the loaded program".into());
+        }
+
+        // A chosen empty-stack SP must leave the program below it (or, growing up, behind it)
+        if let Some(sp) = self.initial_sp {
+            let (low, high) = match self.direction {
+                StackDirection::Down => (code.len() as i16, self.ram.len() as i16),
+                StackDirection::Up => (code.len() as i16 - 1, self.ram.len() as i16 - 1),
+            };
+            if !(low..=high).contains(&sp) {
+                return Err(format!("Initial SP {} is outside the free RAM ({}-{})", sp, low, high).into());
+            }
+        }
+
+        // Load the program (skipping the header) into RAM cleared of any earlier run
+        self.reset();
+        self.program_len = code.len();
+        self.ram[..self.program_len].copy_from_slice(code);
+        self.pool_base = code.len() - pool_len;
+        self.pool_len = pool_len;
+        self.heap_base = code.len();
+        self.heap_top = code.len();
+        self.stack_bottom = self.empty_sp();
+        self.sp = self.stack_bottom;
+        self.fp = self.stack_bottom;
+        self.pc = self.entry as i16;
+
+        Ok(())
+    }
+
+    // Load a program straight from the bytes of a .v file
+    pub fn load_bytes(&mut self, bytes: &[u8]) -> Result<(), VmError> {
+        self.load(&bytes_to_words(bytes)?)
+    }
+
+    // Copy a data block into RAM at word address `addr`, after the loaded
+    // program. An upward stack, and the heap while it is empty, are moved to
+    // start past the block
+    pub fn load_data(&mut self, addr: usize, data: &[u32]) -> Result<(), VmError> {
+        if addr < self.program_len {
+            return Err(format!("(data) block at {} overlaps the program (words 0-{})", addr, self.program_len - 1).into());
+        }
+        let end = addr + data.len();
+        if end > self.ram.len() {
+            return Err(format!("(data) {} word(s) at {} do not fit in {} words of RAM", data.len(), addr, self.ram.len()).into());
+        }
+
+        self.ram[addr..end].copy_from_slice(data);
+        if !self.heap_in_use() {
+            self.heap_base = self.heap_base.max(end);
+            self.heap_top = self.heap_base;
+        }
+        if self.direction == StackDirection::Up && !data.is_empty() {
+            self.stack_bottom = self.stack_bottom.max(end as i16 - 1);
+            self.sp = self.stack_bottom;
+            self.fp = self.stack_bottom;
+        }
+        Ok(())
+    }
+
+    // Call `hook` with the machine and the decoded instruction before each
+    // instruction executes, for custom logging, coverage or UI updates
+    pub fn set_hook(&mut self, hook: InstructionHook<R, W, H>) {
+        self.hook = Some(hook);
+    }
+
+    // Arguments for the program's Argc/Argv instructions; they are kept
+    // across load and reset
+    pub fn set_args(&mut self, args: &[String]) {
+        self.args = args.iter().map(|arg| arg.as_bytes().to_vec()).collect();
+    }
+
+    // Restart the Rand sequence from `seed` (xorshift cannot use 0, so 0 is bumped to 1)
+    pub fn seed_rng(&mut self, seed: u64) {
+        self.rng = seed.max(1);
+    }
+
+    // Clear the machine between programs: RAM is zeroed, the stack emptied and
+    // PC, the cycle count and input status start over
+    pub fn reset(&mut self) {
+        self.ram = [0; 1024];
+        self.program_len = 0;
+        self.stack_bottom = self.empty_sp();
+        self.sp = self.stack_bottom;
+        self.fp = self.stack_bottom;
+        self.pc = 0;
+        self.frames.clear();
+        self.cycles = 0;
+        self.stats = RunStats::default();
+        self.call_depth = 0;
+        self.output.count = 0;
+        self.input_failed = false;
+        self.pool_base = 0;
+        self.pool_len = 0;
+        self.heap_base = 0;
+        self.heap_top = 0;
+        self.recent.clear();
+        self.traps = [None; TRAP_KINDS];
+    }
+
+    // The last few instructions executed, oldest first, one decoded line each;
+    // after an error the last line is the instruction that failed
+    pub fn recent_instructions(&self) -> String {
+        let mut report = String::new();
+        for &(pc, word) in &self.recent {
+            let decoded = match Instruction::decode(word) {
+                Ok(instruction) => instruction.to_string(),
+                Err(e) => format!("<{}>", e),
+            };
+            report += &format!("  {:04x}: {:08x}  {}\n", pc, word, decoded);
+        }
+        report
+    }
+
+    // Summary of the loaded program for diagnostics: size, layout and the
+    // first few decoded instructions
+    pub fn describe(&self) -> String {
+        let mut report = format!(
+            "Loaded {} instruction word(s)\nRAM size: {} words\nInitial SP: {}\nEntry point: {}\nFirst instructions:\n",
+            self.program_len,
+            self.ram.len(),
+            self.sp,
+            self.pc,
+        );
+
+        for (addr, &inst) in self.ram.iter().enumerate().take(self.program_len.min(8)) {
+            let decoded = match Instruction::decode(inst) {
+                Ok(instruction) => format!("{:?}", instruction),
+                Err(e) => format!("<{}>", e),
+            };
+            report += &format!("  {:04x}: {:08x}  {}\n", addr, inst, decoded);
+        }
+
+        report
+    }
+
+    // Contents of RAM (program, data and stack)
+    pub fn ram(&self) -> &[u32] {
+        &self.ram
+    }
+
+    // Counters for the run so far
+    pub fn stats(&self) -> RunStats {
+        RunStats { cycles: self.cycles, output_bytes: self.output.count, ..self.stats }
+    }
+
+    // Current program counter
+    pub fn pc(&self) -> i16 {
+        self.pc
+    }
+
+    // Current stack pointer
+    pub fn sp(&self) -> i16 {
+        self.sp
+    }
+
+    // Live stack words, top first
+    pub fn stack(&self) -> Vec<u32> {
+        (0..self.depth().max(0))
+            .filter_map(|offset| self.stack_addr(offset).ok())
+            .map(|addr| self.ram[addr])
+            .collect()
+    }
+
+    // Live stack, top first, with each word's RAM address and common renderings
+    pub fn stack_view(&self) -> Vec<StackEntry> {
+        (0..self.depth().max(0))
+            .filter_map(|offset| self.stack_addr(offset).ok())
+            .map(|address| {
+                let value = self.ram[address];
+                StackEntry { address, value, as_signed: value as i32, as_hex: format!("{:#010x}", value) }
+            })
+            .collect()
+    }
+
+    // Read one word of RAM by absolute address
+    pub fn read_word(&self, addr: usize) -> Result<u32, VmError> {
+        self.ram.get(addr).copied().ok_or("(mem) address out of bounds".into())
+    }
+
+    // Overwrite one word of RAM by absolute address, e.g. from a debugger
+    pub fn write_word(&mut self, addr: usize, value: u32) -> Result<(), VmError> {
+        let word = self.ram.get_mut(addr).ok_or("(set) address out of bounds")?;
+        *word = value;
+        Ok(())
+    }
+
+    // Run the virtual machine loop
+    pub fn run(&mut self) -> Result<u8, VmError> {
+        let result = loop {
+            match self.step_instruction() {
+                Ok(None) => (),
+                Ok(Some(code)) => break Ok(code),
+                Err(e) => break Err(e),
+            }
+        };
+
+        // Output held back by the flush policy is still shown when the run fails
+        self.flush()?;
+        result
+    }
+
+    // Run until PC reaches `target_pc` (checked before each instruction, so a
+    // machine already there does not move) or the program exits. Returns the
+    // exit code, or None if execution stopped at the target
+    pub fn run_until(&mut self, target_pc: i16) -> Result<Option<u8>, VmError> {
+        while self.pc != target_pc {
+            if let Some(code) = self.step_instruction()? {
+                return Ok(Some(code));
+            }
+        }
+        Ok(None)
+    }
+
+    // Write out any buffered program output and trace records
+    pub fn flush(&mut self) -> Result<(), VmError> {
+        if let Some(out) = self.trace_output.as_mut() {
+            out.flush()?;
+        }
+        Ok(self.output.flush()?)
+    }
+
+    // Execute the instruction at PC, returning the exit code if it was Exit
+    pub fn step_instruction(&mut self) -> Result<Option<u8>, VmError> {
+        self.step_with_instruction().map(|(_, exit)| exit)
+    }
+
+    // Execute the instruction at PC and return it along with the exit code
+    // (if it was Exit), so a caller can follow what ran without decoding again
+    pub fn step_with_instruction(&mut self) -> Result<(Instruction, Option<u8>), VmError> {
+        // Stop runaway programs once the cycle budget is spent
+        if self.max_cycles.is_some_and(|limit| self.cycles >= limit) {
+            return Err("cycle limit exceeded".into());
+        }
+        self.cycles += 1;
+
+        // Code and stack share RAM, so a runaway PC can reach pushed data
+        if self.guard_stack && self.in_stack(self.pc as i32) {
+            return Err(format!("(fetch) PC {} is executing live stack data (SP {})", self.pc, self.sp).into());
+        }
+
+        let instruction = self.fetch()?;
+
+        // Remember the last few instructions (PC and word) for error reports
+        if self.recent.len() == RECENT_INSTRUCTIONS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back((self.pc, self.ram[self.pc as usize]));
+
+        // With sampling, only every Nth executed instruction is traced
+        let sampled = self.trace_sample.is_none_or(|every| self.cycles.is_multiple_of(every));
+        if let Some(format) = self.trace && sampled && !self.quiet {
+            self.trace_instruction(format, &instruction)?;
+        }
+
+        // The hook sees the machine as it is before the instruction runs
+        if let Some(mut hook) = self.hook.take() {
+            hook(self, &instruction);
+            self.hook = Some(hook);
+        }
+
+        Ok((instruction, self.execute(instruction)?))
+    }
+
+    // Carry out one decoded instruction and move PC past it (or to its branch target)
+    fn execute(&mut self, instruction: Instruction) -> Result<Option<u8>, VmError> {
+        match instruction {
+            Instruction::Exit(code) => {
+                self.output.flush()?;
+                return Ok(Some(code));
+            }
+
+            Instruction::ExitStack() => {
+                // Exit with a computed (possibly negative) value; see exit_status
+                let value = self.pop()?;
+                self.output.flush()?;
+                return Ok(Some(exit_status(self.narrow(value))));
+            }
+
+            Instruction::Swap(from, to) => {
+                // Sign-extend the 12-bit offsets
+                let from_offset = (from << 4) >> 2;
+                let to_offset = (to << 4) >> 2;
+
+                // Swap two words in the stack (from and to are relative to SP)
+                let f = self.stack_addr(from_offset >> 2).map_err(|_| "(swap) swap out of bounds")?;
+                let t = self.stack_addr(to_offset >> 2).map_err(|_| "(swap) swap out of bounds")?;
+                self.ram.swap(f, t);
+            }
+
+            Instruction::Nop() => (), 
+
+            Instruction::ClearStack() => {
+                // Drop everything, including any return addresses on the stack
+                self.sp = self.stack_bottom;
+                self.frames.clear();
+                self.call_depth = 0;
+            }
+
+            Instruction::Input(lenient, radix) => {
+                // Read a number (decimal/hex/bin) from user
+                let line = self.read_line()?;
+                let trimmed = line.trim();
+
+                let parsed = match radix {
+                    0 => parse_number(trimmed),
+                    _ => parse_number_strict(trimmed, radix),
+                };
+                self.input_failed = parsed.is_err();
+                let word = match parsed {
+                    Ok(word) => word,
+                    Err(_) if lenient => 0,
+                    Err(message) => return Err(message.into()),
+                };
+
+                self.push(self.narrow(word) as u32)?;
+            }
+
+            Instruction::InputOrFlag() => {
+                // Read a number like Input, but never stop: push the value (0
+                // if the line is not a number) and then 1 if it parsed, else 0
+                let line = self.read_line()?;
+                let parsed = parse_number(line.trim());
+                self.input_failed = parsed.is_err();
+
+                let word = parsed.unwrap_or(0);
+                self.push(self.narrow(word) as u32)?;
+                self.push(parsed.is_ok() as u32)?;
+            }
+
+            Instruction::InputN(count) => {
+                // Read `count` numbers separated by spaces or newlines, pushed in
+                // the order given. Running out of input is an error; numbers past
+                // the last one wanted are dropped along with the rest of their line
+                let mut words = Vec::with_capacity(count as usize);
+                while words.len() < count as usize {
+                    if self.input.at_eof()? {
+                        return Err(format!("(input) expected {} numbers but input ended after {}", count, words.len()).into());
+                    }
+                    let line = self.read_line()?;
+                    for token in line.split_whitespace().take(count as usize - words.len()) {
+                        words.push(parse_number(token)?);
+                    }
+                }
+
+                for word in words {
+                    self.push(self.narrow(word) as u32)?;
+                }
+            }
+
+            Instruction::InputEof() => {
+                // 1 once everything on input has been read, else 0
+                let eof = self.input.at_eof()?;
+                self.push(eof as u32)?;
+            }
+
+            Instruction::InputFailed() => {
+                // 1 if the last Input could not parse its line, else 0
+                self.push(self.input_failed as u32)?;
+            }
+
+            Instruction::CyclesLeft() => {
+                // Instructions that may still run after this one; u32::MAX when unlimited
+                let left = match self.max_cycles {
+                    Some(limit) => u32::try_from(limit - self.cycles).unwrap_or(u32::MAX),
+                    None => u32::MAX,
+                };
+                self.push(left)?;
+            }
+
+            Instruction::Stinput(max_chars, true) => {
+                // Read a line byte for byte, untrimmed, as a length-prefixed string
+                let mut input: Vec<u8> = self.read_line()?.chars().map(|c| c as u8).collect();
+                input.truncate(max_chars as usize);
+                self.push_counted_string(&input)?;
+            }
+
+            Instruction::Stinput(max_chars, false) => {
+
+                // Read a string from input and store it in RAM using 24-bit packing.
+                // read_line maps each input byte to one char, so this recovers the bytes
+                let mut input: Vec<u8> = self.read_line()?.trim().chars().map(|c| c as u8).collect();
+                if input.is_empty() {
+                    self.push(0)?;
+                    return Ok(None);
+                }
+
+                input.truncate(max_chars as usize);
+                self.push_string(input)?;
+            }
+
+            Instruction::SlurpInput(max_words) => {
+                // Read everything left on input as one packed string of at most max_words
+                let mut input = Vec::new();
+                self.input.read_to_end(&mut input)?;
+                self.echo(&input)?;
+                input.truncate(max_words as usize * 3);
+
+                if input.is_empty() {
+                    self.push(0)?;
+                } else {
+                    self.push_string(input)?;
+                }
+            }
+
+            // The address of this instruction, and SP as it was before the push
+            Instruction::PushPc() => self.push(self.pc as u32)?,
+            Instruction::PushSp() => self.push(self.sp as u32)?,
+
+            Instruction::Argc() => self.push(self.args.len() as u32)?,
+
+            Instruction::Argv() => {
+                // Replace the index on top with that argument as a packed string (0 if empty)
+                let index = self.pop()?;
+                let Some(arg) = self.args.get(index as usize).cloned() else {
+                    return Err(format!("(argv) no argument {} ({} given)", index as i32, self.args.len()).into());
+                };
+                if arg.is_empty() {
+                    self.push(0)?;
+                } else {
+                    self.push_string(arg)?;
+                }
+            }
+
+            Instruction::Capacity() => self.push(self.ram.len() as u32)?,
+
+            Instruction::Free() => {
+                // Words that can still be pushed before the stack overflows
+                let free = match self.direction {
+                    StackDirection::Down if self.heap_in_use() => self.sp as i32 - self.heap_top as i32,
+                    StackDirection::Down => self.sp as i32,
+                    StackDirection::Up => self.ram.len() as i32 - 1 - self.sp as i32,
+                };
+                self.push(free.max(0) as u32)?;
+            }
+
+            Instruction::Rand() => {
+                // xorshift64*: deterministic for a given seed
+                self.rng ^= self.rng >> 12;
+                self.rng ^= self.rng << 25;
+                self.rng ^= self.rng >> 27;
+                self.push((self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 32) as u32)?;
+            }
+
+            Instruction::Assert() => {
+                // Self-checking programs stop here when the popped condition is 0
+                if self.pop()? == 0 {
+                    return Err(format!("assertion failed at pc {}", self.pc).into());
+                }
+            }
+
+            Instruction::SetTrapHandler(trap, addr) => {
+                // Address 0xFFFF removes the handler, making the trap an error again
+                let Some(handler) = self.traps.get_mut(trap as usize) else {
+                    return Err(format!("(trap) unknown trap {}", trap).into());
+                };
+                *handler = match addr {
+                    0xFFFF => None,
+                    _ if addr as usize >= self.ram.len() => {
+                        return Err(format!("(trap) handler address {} out of bounds", addr).into());
+                    }
+                    _ => Some(addr as i16),
+                };
+            }
+
+            // Show held-back output now, whatever the flush policy (e.g. before a prompt's input)
+            Instruction::Flush() => self.output.flush()?,
+
+            Instruction::WaitKey() => {
+                // Show the output so far, then wait for one byte of input. Only
+                // when someone is typing: piped input is left for the reads
+                if self.interactive {
+                    self.output.flush()?;
+                    // End of input counts as a key press
+                    let mut key = [0; 1];
+                    match self.input.read_exact(&mut key) {
+                        Err(e) if e.kind() != std::io::ErrorKind::UnexpectedEof => return Err(e.into()),
+                        _ => (),
+                    }
+                }
+            }
+
+            Instruction::Syscall(num) => {
+                // Hand the call off to the embedder's host environment
+                self.host.syscall(num, &mut self.ram, &mut self.sp)?;
+                self.check_sp(self.sp as i32)?;
+            }
+
+            Instruction::Debug(_offset) => {
+                if !self.quiet {
+                    writeln!(self.output, "Debug")?;
+                    self.wrote_output(true)?;
+                }
+            }
+
+            /*
+             * Binary Arithmetic Instructions
+             */
+            Instruction::Add()                  => self.binary_op(|l, r| l.wrapping_add(r))?,
+            Instruction::Subtract()             => self.binary_op(|l, r| l.wrapping_sub(r))?,
+            Instruction::Multiply()             => self.binary_op(|l, r| l.wrapping_mul(r))?,
+            Instruction::Divide() => {
+                if self.division_op(|l, r| l.wrapping_div(r))? {
+                    return Ok(None);
+                }
+            }
+            Instruction::Remainder() => {
+                if self.division_op(|l, r| l.wrapping_rem(r))? {
+                    return Ok(None);
+                }
+            }
+            Instruction::And()                  => self.binary_op(|l, r| l & r)?,
+            Instruction::Or()                   => self.binary_op(|l, r| l | r)?,
+            Instruction::Xor()                  => self.binary_op(|l, r| l ^ r)?,
+            Instruction::LogicalLeftShift()     => self.binary_op(|l, r| l.wrapping_shl(r as u32))?,
+            Instruction::LogicalRightShift()    => self.binary_op(|l, r| l.wrapping_shr(r as u32))?,
+            Instruction::ArithmeticRightShift() => self.binary_op(|l, r| l.wrapping_shr(r as u32))?,
+            Instruction::SatAdd()               => self.saturating_op(|l, r| l + r)?,
+            Instruction::SatSubtract()          => self.saturating_op(|l, r| l - r)?,
+            Instruction::SatMultiply()          => self.saturating_op(|l, r| l * r)?,
+            Instruction::MulHigh() => {
+                let bits = self.word_bits();
+                self.binary_op(move |l, r| ((l as i64 * r as i64) >> bits) as i32)?
+            }
+            Instruction::MulHighU() => {
+                let (bits, mask) = (self.word_bits(), u64::MAX >> (64 - self.word_bits()));
+                self.binary_op(move |l, r| (((l as u64 & mask) * (r as u64 & mask)) >> bits) as i32)?
+            }
+
+            /*
+             * Unary Arithmetic Instructions
+             */
+            Instruction::Not() => self.unary_op(|x| !x)?,
+            Instruction::Negate() => self.unary_op(|x| x.wrapping_neg())?,
+
+            Instruction::Pop(offset) => {
+
+                // Pop offset bytes (in 4-byte words) from the stack, stopping at the bottom
+                let words = (offset >> 2).min(self.depth() as u32);
+                self.shrink(words as i32)?;
+            }
+
+            Instruction::Goto(offset) => {
+                self.jump(offset)?;
+                return Ok(None);
+            }
+
+            Instruction::Stprint(offset, true) => {
+                // Print a length-prefixed string: the byte count, then its words deeper in the stack
+                let start = self.stack_addr((offset >> 2) as i16)?;
+                let len = self.ram[start] as usize;
+
+                // The count is an unchecked word, so check it against the RAM
+                // beyond `start` before reserving room for the string
+                let words_left = match self.direction {
+                    StackDirection::Down => self.ram.len() - 1 - start,
+                    StackDirection::Up => start,
+                };
+                if len.div_ceil(4) > words_left {
+                    return Err(format!("(stprint) {}-byte string at {} runs past the edge of RAM", len, start).into());
+                }
+
+                let mut bytes = Vec::with_capacity(len);
+                for word in 1..=len.div_ceil(4) {
+                    let addr = start as i32 + word as i32 * self.deeper() as i32;
+                    bytes.extend(self.ram[addr as usize].to_le_bytes());
+                }
+                bytes.truncate(len);
+
+                self.output.write_all(&bytes)?;
+                self.wrote_output(bytes.contains(&b'\n'))?;
+            }
+
+            Instruction::Stprint(offset, false) => {
+
+                // Print a packed string from RAM starting at offset
+                let mut idx = self.stack_addr((offset >> 2) as i16)?;
+                let mut newline = false;
+                loop {
+                    let cur_word = self.ram[idx];
+
+                    if cur_word == 0 {
+                        break;
+                    }
+
+                    let bytes = cur_word.to_le_bytes(); 
+                    for &b in &bytes {
+                        if b != 1 {
+                            self.output.write_all(&[b])?;
+                            newline |= b == b'\n';
+                        }
+                    }
+                    // Stop at the terminator or the edge of RAM
+                    let next = idx as i32 + self.deeper() as i32;
+                    if bytes[3] == 0 || idx == 0 || !(0..1024).contains(&next) {
+                        break;
+                    }
+                    idx = next as usize;
+                }
+
+                self.wrote_output(newline)?;
+            }
+
+
+            Instruction::Call(offset) => {
+                // Push return address (next PC)
+                let return_address = (self.pc + 1) as u32;
+                self.push(return_address)?;
+                self.stats.calls += 1;
+                self.call_depth += 1;
+                self.stats.peak_call_depth = self.stats.peak_call_depth.max(self.call_depth);
+
+                // Remember where the return address lives so Return can check the frame
+                if self.frame_check != FrameCheck::Off {
+                    self.frames.push((return_address, self.sp));
+                }
+
+                // Jump to offset
+                self.jump(offset)?;
+                return Ok(None);
+            }
+
+            Instruction::Return(offset) => {
+                // The return address and the words above it must all be on the stack
+                let offset_words = offset / 4;
+                if offset_words >= self.depth().max(0) as u32 {
+                    return Err("(return) return pop exceeds stack".into());
+                }
+                let offset_words = offset_words as i16;
+
+                // Calculate address location using offset
+                let addr_index = self.stack_addr(offset_words)?;
+
+                self.check_frame(addr_index as i16)?;
+
+                // Pop the return address
+                let addr = self.ram[addr_index];
+
+                // Free the frame
+                self.shrink(1 + offset_words as i32)?;
+                self.stats.returns += 1;
+                self.call_depth = self.call_depth.saturating_sub(1);
+
+                self.pc = addr as i16;
+                return Ok(None);
+            }
+
+            /*
+             * Binary If Instructions
+             */
+            Instruction::BinaryIf(cond, offset) => {
+                // Both operands are peeked, not popped
+                if self.depth() < 2 {
+                    return Err("(binary if) stack underflow: comparison needs two words".into());
+                }
+                let right = self.narrow(self.ram[self.stack_addr(0)?]) as u32;
+                let left = self.narrow(self.ram[self.stack_addr(1)?]) as u32;
+
+                let taken = match cond {
+                    0 => left == right,
+                    1 => left != right,
+                    2 => left < right,
+                    3 => left > right,
+                    4 => left <= right,
+                    5 => left >= right,
+                    _ => false,
+                };
+                if taken {
+                    self.jump(offset)?;
+                    return Ok(None);
+                }
+            }
+
+            /*
+             * Unary If Instructions
+             */
+            Instruction::EqZero(offset) => {
+                if self.unary_if(offset, |x| x == 0)? {
+                    return Ok(None);
+                }
+            }
+            Instruction::NeZero(offset) => {
+                if self.unary_if(offset, |x| x != 0)? {
+                    return Ok(None);
+                }
+            }
+            Instruction::GeZero(offset) => {
+                if self.unary_if(offset, |x| x >= 0)? {
+                    return Ok(None);
+                }
+            }
+            Instruction::IfOdd(offset) => {
+                if self.unary_if(offset, |x| x & 1 != 0)? {
+                    return Ok(None);
+                }
+            }
+            Instruction::IfEven(offset) => {
+                if self.unary_if(offset, |x| x & 1 == 0)? {
+                    return Ok(None);
+                }
+            }
+            // Branch on whether the stack is empty, without reading it
+            Instruction::IfEmpty(offset) | Instruction::IfNotEmpty(offset) => {
+                let empty = self.depth() <= 0;
+                if empty == matches!(instruction, Instruction::IfEmpty(_)) {
+                    self.jump(offset >> 2)?;
+                    return Ok(None);
+                }
+            }
+            Instruction::LtZero(offset) => {
+                if self.unary_if(offset, |x| x < 0)? {
+                    return Ok(None);
+                }
+            }
+
+            Instruction::Dup(offset) => {
+                let idx = self.stack_addr((offset >> 2) as i16)?;
+                let val = self.ram[idx];
+                self.push(val)?;
+            }
+
+            Instruction::DupN(offset, count) => {
+                // Copy the block out first so it lands on top in the same order
+                let first = (offset >> 2) as i16;
+                let block = (0..count as i16)
+                    .map(|i| self.stack_addr(first + i).map(|addr| self.ram[addr]))
+                    .collect::<Result<Vec<u32>, _>>()?;
+
+                for &word in block.iter().rev() {
+                    self.push(word)?;
+                }
+            }
+
+            Instruction::NumToStr() => {
+                // Replace the top word with its signed decimal text as a packed string
+                let text = (self.pop()? as i32).to_string();
+                self.push_string(text.into_bytes())?;
+            }
+
+            Instruction::StrToNum() => {
+                // Replace a packed string with its value (parsed like Input) and,
+                // on top, 1 if it parsed or 0 if not (the value is then 0)
+                let bytes = self.pop_string()?;
+                let text: String = bytes.iter().map(|&b| b as char).collect();
+                let parsed = parse_number(text.trim());
+                self.push(*parsed.as_ref().unwrap_or(&0))?;
+                self.push(parsed.is_ok() as u32)?;
+            }
+
+            Instruction::DupTop(count) => {
+                // Push `count` more copies of the top word
+                if self.depth() < 1 {
+                    return Err("(duptop) stack underflow: nothing to copy".into());
+                }
+                let top = self.ram[self.stack_addr(0)?];
+                for _ in 0..count {
+                    self.push(top)?;
+                }
+            }
+
+            Instruction::BitTest(bit, offset) => {
+                // Branch if the bit is set, leaving the word on the stack
+                if self.unary_if(offset << 2, move |x| (x >> bit) & 1 == 1)? {
+                    return Ok(None);
+                }
+            }
+
+            // With c on top: rotl3 turns a b c into b c a, rotr3 turns a b c into c a b
+            Instruction::RotLeft3() => self.rotate3(true)?,
+            Instruction::RotRight3() => self.rotate3(false)?,
+
+            Instruction::Compare(cond, signed) => {
+                // Replace the top two words with 1 if `second cond top` holds, else 0
+                if self.depth() < 2 {
+                    return Err("(compare) stack underflow: comparison needs two words".into());
+                }
+                let (right, left) = (self.pop()?, self.pop()?);
+                let (right, left) = (self.narrow(right), self.narrow(left));
+                let ordering = if signed {
+                    left.cmp(&right)
+                } else {
+                    (left as u32).cmp(&(right as u32))
+                };
+
+                let holds = match cond {
+                    0 => ordering.is_eq(),
+                    1 => ordering.is_ne(),
+                    2 => ordering.is_lt(),
+                    3 => ordering.is_gt(),
+                    4 => ordering.is_le(),
+                    _ => ordering.is_ge(),
+                };
+                self.push(holds as u32)?;
+            }
+
+            // Pop the top word and, if the condition holds, step over the next instruction
+            Instruction::SkipIfZero() => {
+                let top = self.pop()?;
+                if self.narrow(top) == 0 {
+                    self.step();
+                }
+            }
+            Instruction::SkipIfNonZero() => {
+                let top = self.pop()?;
+                if self.narrow(top) != 0 {
+                    self.step();
+                }
+            }
+
+            Instruction::DupOp(func) => {
+                // Apply the binary function to the top word and a copy of it (e.g. mul squares)
+                if self.depth() < 1 {
+                    return Err("(dupop) stack underflow: nothing to copy".into());
+                }
+                let op = Instruction::decode(0x2000_0000 | (func << 24))?;
+                self.push(self.ram[self.stack_addr(0)?])?;
+                return self.execute(op);
+            }
+
+            Instruction::CMov() => {
+                // With `a b cond` on top of the stack, replace all three with a
+                // if cond is nonzero, else with b
+                if self.depth() < 3 {
+                    return Err("(cmov) stack underflow: needs two values and a condition".into());
+                }
+                let (cond, second, first) = (self.pop()?, self.pop()?, self.pop()?);
+                self.push(if self.narrow(cond) != 0 { first } else { second })?;
+            }
+
+            Instruction::PushConst(index) => {
+                // The pool is the last words of a version 3 program
+                if index as usize >= self.pool_len {
+                    return Err(format!("(const) no constant {} in a pool of {}", index, self.pool_len).into());
+                }
+                self.push(self.ram[self.pool_base + index as usize])?;
+            }
+
+            /*
+             * Frame Pointer Instructions
+             */
+            Instruction::EnterFrame() => {
+                // Save the caller's FP, then point FP at the saved copy
+                self.push(self.fp as u32)?;
+                self.fp = self.sp;
+            }
+
+            Instruction::LeaveFrame() => {
+                // Drop the locals and restore the caller's FP
+                self.sp = self.check_sp(self.fp as i32)?;
+                self.fp = self.pop()? as i16;
+            }
+
+            Instruction::LoadFp(offset) => {
+                let addr = self.fp_addr(offset)?;
+                self.push(self.ram[addr])?;
+            }
+
+            Instruction::StoreFp(offset) => {
+                let addr = self.fp_addr(offset)?;
+                self.ram[addr] = self.pop()?;
+            }
+
+            Instruction::LoadAbs(base) => {
+                // Replace the index on top with the word at base + index
+                let index = self.pop()?;
+                let addr = self.abs_addr(base, index)?;
+                self.push(self.ram[addr])?;
+            }
+
+            Instruction::StoreAbs(base) => {
+                // With the index on top and the value beneath it, store the value at base + index
+                let index = self.pop()?;
+                let addr = self.abs_addr(base, index)?;
+                self.check_null_write(addr)?;
+                self.ram[addr] = self.pop()?;
+            }
+
+            Instruction::MemCopy() => {
+                // Pop destination, source and length (in words); the ranges may overlap
+                let dest = self.pop()?;
+                let src = self.pop()?;
+                let len = self.pop()?;
+                if len > 0 {
+                    let last_src = self.abs_addr(src, len - 1)?;
+                    self.abs_addr(dest, len - 1)?;
+                    self.check_null_write(dest as usize)?;
+                    self.ram.copy_within(src as usize..=last_src, dest as usize);
+                }
+            }
+
+            Instruction::LoadByte(base, signed) => {
+                // Replace the index on top with the byte at base + index, widened to a word
+                let index = self.pop()?;
+                let byte = self.read_byte(base, index)?;
+                self.push(if signed { byte as i8 as u32 } else { byte as u32 })?;
+            }
+
+            Instruction::Alloc(words) => {
+                // Push the word address of a new zeroed block
+                let addr = self.alloc(words)?;
+                self.push(addr as u32)?;
+            }
+
+            Instruction::HeapFree() => {
+                let addr = self.pop()?;
+                self.free(addr as usize)?;
+            }
+
+            /*
+             * 64-bit Arithmetic Instructions
+             */
+            Instruction::Add64() => self.wide_op(|l, r| l.wrapping_add(r))?,
+            Instruction::Sub64() => self.wide_op(|l, r| l.wrapping_sub(r))?,
+            Instruction::Mul64() => self.wide_op(|l, r| l.wrapping_mul(r))?,
+
+            Instruction::Print(offset, newline) => {
+                let idx = self.stack_addr((offset >> 2) as i16)?;
+
+                let radix = match offset & 0b11 {
+                    0b00 => 10,
+                    0b01 => 16,
+                    0b10 => 2,
+                    _ => 8,
+                };
+
+                // Decimal is signed; other bases show the word width's bit pattern
+                let val = match radix {
+                    10 => self.narrow(self.ram[idx]) as u32,
+                    _ => self.ram[idx] & (u32::MAX >> (32 - self.word_bits())),
+                };
+                write!(self.output, "{}", self.formatter.format(val, radix))?;
+                if newline {
+                    writeln!(self.output)?;
+                }
+                self.wrote_output(newline)?;
+            },
+
+            Instruction::Dump() => {
+                if self.depth() == 0 {
+                    // stack empty (nop)
+
+                } else {
+                    for address in 0..self.depth() {
+                        let value = self.ram[self.stack_addr(address)?];
+                        writeln!(self.output, "{:04x}: {:08x}", address, value)?;
+                    }
+
+                    self.wrote_output(true)?;
+                }
+
+            }
+
+
+            Instruction::DumpLine() => {
+                // Every live word on one line, top first; an empty stack gives an empty line
+                let mut words = Vec::new();
+                for offset in 0..self.depth() {
+                    words.push(format!("{:08x}", self.ram[self.stack_addr(offset)?]));
+                }
+                writeln!(self.output, "{}", words.join(" "))?;
+                self.wrote_output(true)?;
+            }
+
+            Instruction::Push(val) => self.push(val)?, 
+
+            // A valid encoding with no instruction assigned to it yet
+            Instruction::Reserved(word) => {
+                return Err(format!("reserved instruction {:08x} at PC {}", word, self.pc).into());
+            }
+        }
+
+        self.step();
+        Ok(None)
+    }
+
+    /*
+     * Binary arithmetic helper function
+     */
+    fn binary_op<F>(&mut self, op: F) -> Result<(), VmError>
+    where
+        F: Fn(i32, i32) -> i32,
+        {
+            // Get right operand
+            let right = self.narrow(self.ram[self.stack_addr(0)?]);
+
+            // Get left operand
+            let left = self.narrow(self.ram[self.stack_addr(1)?]);
+
+            // Apply binary operation to operands, replacing both with the result
+            let result = self.narrow(op(left, right) as u32);
+
+            self.shrink(1)?;
+            let top = self.stack_addr(0)?;
+            self.ram[top] = result as u32;
+            Ok(())
+        }
+
+    /*
+     * Saturating arithmetic helper function (clamps to the word width's range)
+     */
+    fn saturating_op<F>(&mut self, op: F) -> Result<(), VmError>
+    where
+        F: Fn(i64, i64) -> i64,
+        {
+            let max = (1i64 << (self.word_bits() - 1)) - 1;
+            self.binary_op(move |l, r| op(l as i64, r as i64).clamp(-max - 1, max) as i32)
+        }
+
+    /*
+     * Divide/remainder helper function
+     *
+     * A zero divisor is an error unless the program installed a handler for
+     * it. Dividing the most negative word by -1 wraps unless a handler is
+     * installed for that trap. Returns true if a trap handler took over.
+     */
+    fn division_op<F>(&mut self, op: F) -> Result<bool, VmError>
+    where
+        F: Fn(i32, i32) -> i32,
+        {
+            let right = self.narrow(self.ram[self.stack_addr(0)?]);
+            let left = self.narrow(self.ram[self.stack_addr(1)?]);
+
+            if right == 0 {
+                return self.trap(TRAP_DIVIDE_BY_ZERO, "(divide) division by zero");
+            }
+            let min = -(1i64 << (self.word_bits() - 1));
+            if left as i64 == min && right == -1 && self.traps[TRAP_DIVIDE_OVERFLOW].is_some() {
+                return self.trap(TRAP_DIVIDE_OVERFLOW, "(divide) division overflow");
+            }
+            self.binary_op(op)?;
+            Ok(false)
+        }
+
+    // Raise a trap: with no handler installed it ends the run with `message`.
+    // Otherwise the operands stay on the stack, the address of the next
+    // instruction and then the trap number are pushed, and execution continues
+    // at the handler (which can `pop 4` and `return 0` to resume). Delivery
+    // counts as a call, so the handler's Return is checked against this frame
+    fn trap(&mut self, trap: usize, message: &str) -> Result<bool, VmError> {
+        let Some(handler) = self.traps[trap] else {
+            return Err(message.into());
+        };
+        let return_address = (self.pc + 1) as u32;
+        self.push(return_address)?;
+        self.stats.calls += 1;
+        self.call_depth += 1;
+        self.stats.peak_call_depth = self.stats.peak_call_depth.max(self.call_depth);
+        if self.frame_check != FrameCheck::Off {
+            self.frames.push((return_address, self.sp));
+        }
+        self.push(trap as u32)?;
+        self.pc = handler;
+        Ok(true)
+    }
+
+    /*
+     * 64-bit arithmetic helper function
+     *
+     * A 64-bit value is a pair of words: the low word is pushed first and the
+     * high word sits above it. The right operand is the pair on top of the
+     * stack, the left operand the pair below it, and both are replaced by the
+     * result pair.
+     */
+    fn wide_op<F>(&mut self, op: F) -> Result<(), VmError>
+    where
+        F: Fn(i64, i64) -> i64,
+        {
+            if self.depth() < 4 {
+                return Err("(wide) stack underflow: needs two 64-bit operands".into());
+            }
+            let right = self.pop_i64()?;
+            let left = self.pop_i64()?;
+            self.push_i64(op(left, right))
+        }
+
+    // Pop a 64-bit value (high word on top, low word below)
+    fn pop_i64(&mut self) -> Result<i64, VmError> {
+        let high = self.pop()? as u64;
+        let low = self.pop()? as u64;
+        Ok(((high << 32) | low) as i64)
+    }
+
+    // Push a 64-bit value (low word first, then high word)
+    fn push_i64(&mut self, value: i64) -> Result<(), VmError> {
+        self.push(value as u32)?;
+        self.push((value >> 32) as u32)
+    }
+
+    /*
+     * Unary arithmetic helper function
+     */
+    fn unary_op<F>(&mut self, op: F) -> Result<(), VmError>
+    where
+        F: Fn(i32) -> i32,
+        {
+            let top = self.stack_addr(0)?;
+            let val = self.narrow(self.ram[top]);
+
+            let result = self.narrow(op(val) as u32);
+            self.ram[top] = result as u32;
+            Ok(())
+        }
+
+    /*
+     * Unary If helper function
+     */
+    fn unary_if<F>(&mut self, offset: i32, cond: F) -> Result<bool, VmError>
+    where
+        F: Fn(i32) -> bool,
+        {
+            // The operand is peeked, not popped
+            if self.depth() < 1 {
+                return Err("(unary if) stack underflow: test needs one word".into());
+            }
+            let val = self.narrow(self.ram[self.stack_addr(0)?]);
+            if cond(val) {
+                self.jump(offset >> 2)?;
+                return Ok(true); // Jump occurred
+            }
+            Ok(false)
+        }
+
+    /*
+     * Stack frame balance check
+     *
+     * Compares where Return expects the return address to be against
+     * where the matching Call actually pushed it.
+     */
+    fn check_frame(&mut self, addr_sp: i16) -> Result<(), VmError> {
+        if self.frame_check == FrameCheck::Off {
+            return Ok(());
+        }
+
+        // A Return with no tracked Call (e.g. a hand-built frame) can't be checked
+        let Some((return_address, call_sp)) = self.frames.pop() else {
+            return Ok(());
+        };
+
+        // Words left on the stack above the return address
+        let extra = (call_sp - addr_sp) * self.deeper();
+        if extra != 0 {
+            let message = format!(
+                "(return) unbalanced stack returning to {}: {} word(s) {} than at call",
+                return_address,
+                extra.abs(),
+                if extra > 0 { "more" } else { "fewer" },
+            );
+
+            if self.frame_check == FrameCheck::Strict {
+                return Err(message.into());
+            }
+            if !self.quiet {
+                eprintln!("warning: {}", message);
+            }
+        }
+
+        Ok(())
+    }
+
+    // Write the trace record for the instruction about to execute at PC
+    fn trace_instruction(&mut self, format: TraceFormat, instruction: &Instruction) -> Result<(), VmError> {
+        let word = self.ram[self.pc as usize];
+        let top = match self.depth() {
+            0 => None,
+            _ => self.stack_addr(0).ok().map(|addr| self.ram[addr]),
+        };
+
+        let mut record = Vec::new();
+        match format {
+            TraceFormat::Human => {
+                let top = top.map_or(String::from("-"), |value| format!("{:#x}", value));
+                let mut text = format!("{:<24}", instruction.to_string());
+                if self.color && self.trace_output.is_none() {
+                    text = paint(word >> 28, &text);
+                }
+                writeln!(record, "{:04x}: {:08x}  {} sp={:<5} top={}",
+                    self.pc, word, text, self.sp, top)?;
+            }
+            TraceFormat::Jsonl => {
+                let top = top.map_or(String::new(), |value| format!(",\"stack_top\":{}", value));
+                writeln!(record, "{{\"pc\":{},\"sp\":{},\"opcode\":{},\"mnemonic\":\"{}\"{}}}",
+                    self.pc, self.sp, word >> 28, instruction.mnemonic(), top)?;
+            }
+            TraceFormat::Binary => {
+                if !self.trace_started {
+                    record = binary_trace_header();
+                }
+                let entry = BinaryTraceRecord { pc: self.pc as u16, sp: self.sp as u16, opcode: (word >> 28) as u8 };
+                record.extend(entry.to_bytes());
+            }
+        }
+        self.trace_started = true;
+
+        match self.trace_output.as_mut() {
+            Some(out) => out.write_all(&record)?,
+            None => std::io::stderr().write_all(&record)?,
+        }
+        Ok(())
+    }
+
+    // Move PC by a branch offset in words. With strict_branches set, a target
+    // outside the loaded program (usually an offset that was not word-scaled)
+    // is an error instead of a jump into data or the stack.
+    fn jump(&mut self, words: i32) -> Result<(), VmError> {
+        // Offsets reach well past an i16, so work out the target at full width
+        // rather than letting it wrap back into RAM
+        let target = self.pc as i32 + words;
+        if !(0..self.ram.len() as i32).contains(&target) {
+            return Err(format!("(branch) target {} from PC {} is outside RAM", target, self.pc).into());
+        }
+        if self.strict_branches && !(0..self.program_len as i32).contains(&target) {
+            return Err(format!("(branch) target {} from PC {} is outside the program", target, self.pc).into());
+        }
+        self.pc = target as i16;
+        Ok(())
+    }
+
+    // Increment program counter
+    fn step(&mut self) {
+        self.pc += 1;
+    }
+
+    // A word as arithmetic sees it: sign-extended from 16 bits in 16-bit mode
+    fn narrow(&self, word: u32) -> i32 {
+        match self.word_width {
+            WordWidth::Bits32 => word as i32,
+            WordWidth::Bits16 => word as u16 as i16 as i32,
+        }
+    }
+
+    // Number of bits in an arithmetic value
+    fn word_bits(&self) -> u32 {
+        match self.word_width {
+            WordWidth::Bits32 => 32,
+            WordWidth::Bits16 => 16,
+        }
+    }
+
+    // Flush after an output instruction, as the flush policy asks, and stop the
+    // program once it has written more than max_output bytes in total
+    fn wrote_output(&mut self, newline: bool) -> Result<(), VmError> {
+        if self.max_output.is_some_and(|limit| self.output.count > limit) {
+            return Err("output limit exceeded".into());
+        }
+
+        let now = match self.flush {
+            FlushPolicy::Always => true,
+            FlushPolicy::Line => newline,
+            FlushPolicy::OnExit => false,
+        };
+        if now {
+            self.output.flush()?;
+        }
+        Ok(())
+    }
+
+    // Mirror consumed input bytes to the output when echo_input is set
+    fn echo(&mut self, bytes: &[u8]) -> Result<(), VmError> {
+        if !self.echo_input || bytes.is_empty() {
+            return Ok(());
+        }
+        self.output.write_all(bytes)?;
+        self.wrote_output(bytes.contains(&b'\n'))
+    }
+
+    // Push a string as packed words: three bytes per word, padded with 0x01,
+    // the first bytes on top and the continuation bit set on all but the last word
+    //
+    // Byte value 1 is the padding sentinel, which Stprint skips, so a 0x01 in
+    // the string itself is lost; push_counted_string keeps every byte
+    fn push_string(&mut self, mut bytes: Vec<u8>) -> Result<(), VmError> {
+        // Pad to 3-byte alignment with sentinel value 0x01
+        while !bytes.len().is_multiple_of(3) {
+            bytes.push(0x01);
+        }
+
+        // Encode string backwards into stack
+        for (i, chunk) in bytes.rchunks(3).enumerate() {
+            let word = ((chunk[2] as u32) << 16)
+                | ((chunk[1] as u32) << 8)
+                | (chunk[0] as u32)
+                | if i != 0 { 0x0100_0000 } else { 0 };
+
+            self.push(word)?;
+        }
+
+        Ok(())
+    }
+
+    // Push a length-prefixed string: its bytes four to a word (little-endian,
+    // the last word zero-padded), with the first word nearest the top, then
+    // the byte count on top
+    fn push_counted_string(&mut self, bytes: &[u8]) -> Result<(), VmError> {
+        for chunk in bytes.chunks(4).rev() {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            self.push(u32::from_le_bytes(word))?;
+        }
+        self.push(bytes.len() as u32)
+    }
+
+    // Rotate the top three words, bringing the third word up to the top
+    // (left) or sending the top word down to third (right)
+    fn rotate3(&mut self, left: bool) -> Result<(), VmError> {
+        if self.depth() < 3 {
+            return Err("(rotate) stack underflow: rotation needs three words".into());
+        }
+        let addrs = [self.stack_addr(2)?, self.stack_addr(1)?, self.stack_addr(0)?];
+        let mut words = addrs.map(|addr| self.ram[addr]);
+        if left {
+            words.rotate_left(1);
+        } else {
+            words.rotate_right(1);
+        }
+        for (addr, word) in addrs.into_iter().zip(words) {
+            self.ram[addr] = word;
+        }
+        Ok(())
+    }
+
+    // Pop a packed string (the layout push_string writes) and return its bytes
+    fn pop_string(&mut self) -> Result<Vec<u8>, VmError> {
+        let mut bytes = Vec::new();
+        loop {
+            let word = self.pop()?;
+            let [low, mid, high, more] = word.to_le_bytes();
+            bytes.extend([low, mid, high].iter().filter(|&&b| b != 0x01 && b != 0));
+            if more != 0x01 {
+                return Ok(bytes);
+            }
+        }
+    }
+
+    // Push a value onto the stack
+    fn push(&mut self, word: u32) -> Result<(), VmError> {
+        // Checking the new SP against RAM also catches a corrupt resumed state
+        let next = self.sp as i32 - self.deeper() as i32;
+        if !(0..1024).contains(&next) {
+            return Err("Overflow".into());
+        }
+        if self.direction == StackDirection::Down && self.heap_in_use() && next < self.heap_top as i32 {
+            return Err(format!("heap/stack collision: SP {} would enter the heap, which ends at {}", next, self.heap_top).into());
+        }
+        self.sp = next as i16;
+        self.ram[self.sp as usize] = word;
+        self.stats.peak_depth = self.stats.peak_depth.max(self.depth());
+        Ok(())
+    }
+
+    // Pop the top word off the stack
+    fn pop(&mut self) -> Result<u32, VmError> {
+        if self.depth() < 1 {
+            return Err("Underflow".into());
+        }
+        let word = self.ram[self.stack_addr(0)?];
+        self.shrink(1)?;
+        Ok(word)
+    }
+
+    // SP of an empty stack: one word past its bottom
+    fn empty_sp(&self) -> i16 {
+        if let Some(sp) = self.initial_sp && self.program_len > 0 {
+            return sp;
+        }
+        match self.direction {
+            StackDirection::Down => 1024,
+            StackDirection::Up => self.program_len as i16 - 1,
+        }
+    }
+
+    // SP step that moves one word deeper into the stack (toward its bottom)
+    fn deeper(&self) -> i16 {
+        match self.direction {
+            StackDirection::Down => 1,
+            StackDirection::Up => -1,
+        }
+    }
+
+    // RAM address of the word `words` below the top of the stack
+    fn stack_addr(&self, words: i16) -> Result<usize, VmError> {
+        let addr = self.sp as i32 + words as i32 * self.deeper() as i32;
+        if !(0..self.ram.len() as i32).contains(&addr) {
+            return Err("(stack) address out of bounds".into());
+        }
+        Ok(addr as usize)
+    }
+
+    // RAM address of an FP-relative byte offset (positive is deeper in the stack)
+    fn fp_addr(&self, offset: i32) -> Result<usize, VmError> {
+        let addr = self.fp as i32 + (offset >> 2) * self.deeper() as i32;
+        if !(0..self.ram.len() as i32).contains(&addr) {
+            return Err("(frame) address out of bounds".into());
+        }
+        Ok(addr as usize)
+    }
+
+    // RAM address `index` words past the absolute word address `base`
+    fn abs_addr(&self, base: u32, index: u32) -> Result<usize, VmError> {
+        match base.checked_add(index) {
+            Some(addr) if (addr as usize) < self.ram.len() => Ok(addr as usize),
+            _ => Err(format!("(memory) address {}+{} out of bounds", base, index as i32).into()),
+        }
+    }
+
+    // With trap_null_write set, a store to address 0 (usually an uninitialized
+    // pointer, and always the first instruction) is an error
+    fn check_null_write(&self, addr: usize) -> Result<(), VmError> {
+        if self.trap_null_write && addr == 0 {
+            return Err(format!("(memory) write to address 0 at PC {}", self.pc).into());
+        }
+        Ok(())
+    }
+
+    // Whether any heap block, live or freed, is still below the heap top
+    fn heap_in_use(&self) -> bool {
+        self.heap_top > self.heap_base
+    }
+
+    // Allocate a zeroed block of `words` words and return its address.
+    //
+    // The heap grows up from the end of the program (and any data block)
+    // toward the stack. Each block is preceded by a header word holding its
+    // length, with HEAP_LIVE set while it is allocated. The first freed block
+    // that is large enough is reused whole; otherwise the heap is extended.
+    fn alloc(&mut self, words: u32) -> Result<usize, VmError> {
+        if self.direction != StackDirection::Down {
+            return Err("(heap) alloc needs the stack to grow down".into());
+        }
+        if words == 0 {
+            return Err("(heap) alloc of 0 words".into());
+        }
+
+        let mut header = self.heap_base;
+        while header < self.heap_top {
+            let len = self.ram[header] & !HEAP_LIVE;
+            let next = header + 1 + len as usize;
+            if len == 0 || next > self.heap_top {
+                return Err(format!("(heap) corrupt block header at {}", header).into());
+            }
+            if self.ram[header] & HEAP_LIVE == 0 && len >= words {
+                self.ram[header] |= HEAP_LIVE;
+                self.ram[header + 1..next].fill(0);
+                return Ok(header + 1);
+            }
+            header = next;
+        }
+
+        let top = self.heap_top + 1 + words as usize;
+        if top > self.sp as usize {
+            return Err(format!("heap/stack collision: the heap would end at {} but SP is {}", top, self.sp).into());
+        }
+        self.ram[header] = words | HEAP_LIVE;
+        self.ram[header + 1..top].fill(0);
+        self.heap_top = top;
+        Ok(header + 1)
+    }
+
+    // Release a block returned by alloc; the last block also gives its words back
+    fn free(&mut self, addr: usize) -> Result<(), VmError> {
+        // Follow the headers from the heap base, so an address inside a block
+        // (whose word before may look like a header) is not taken for one
+        let mut header = self.heap_base;
+        while header + 1 < addr && header < self.heap_top {
+            let len = self.ram[header] & !HEAP_LIVE;
+            if len == 0 {
+                return Err(format!("(heap) corrupt block header at {}", header).into());
+            }
+            header += 1 + len as usize;
+        }
+        let live = header + 1 == addr && header < self.heap_top && self.ram[header] & HEAP_LIVE != 0;
+        if !live {
+            return Err(format!("(heap) free of {}, which is not an allocated block", addr).into());
+        }
+
+        self.ram[header] &= !HEAP_LIVE;
+        if addr + self.ram[header] as usize == self.heap_top {
+            self.heap_top = header;
+        }
+        Ok(())
+    }
+
+    // Byte `index` past byte address `base`, viewing RAM as bytes the way a
+    // .v file stores it: each word little-endian, so byte 0 is the low byte of word 0
+    fn read_byte(&self, base: u32, index: u32) -> Result<u8, VmError> {
+        match base.checked_add(index) {
+            Some(addr) if (addr as usize) < self.ram.len() * 4 => {
+                Ok(self.ram[addr as usize / 4].to_le_bytes()[addr as usize % 4])
+            }
+            _ => Err(format!("(memory) byte address {}+{} out of bounds", base, index as i32).into()),
+        }
+    }
+
+    // Move SP `words` toward the bottom of the stack (negative grows it)
+    fn shrink(&mut self, words: i32) -> Result<(), VmError> {
+        self.sp = self.check_sp(self.sp as i32 + words * self.deeper() as i32)?;
+        Ok(())
+    }
+
+    // Validate a stack pointer: anywhere from full to empty
+    fn check_sp(&self, sp: i32) -> Result<i16, VmError> {
+        let (low, high) = match self.direction {
+            StackDirection::Down => (0, self.stack_bottom as i32),
+            StackDirection::Up => (self.stack_bottom as i32, self.ram.len() as i32 - 1),
+        };
+        if !(low..=high).contains(&sp) {
+            return Err("(stack) stack pointer out of bounds".into());
+        }
+        Ok(sp as i16)
+    }
+
+    // Number of words on the stack
+    fn depth(&self) -> i16 {
+        self.stack_bottom.wrapping_sub(self.sp).wrapping_mul(self.deeper())
+    }
+
+    // Whether a RAM address currently holds a live stack word
+    fn in_stack(&self, addr: i32) -> bool {
+        let (sp, bottom) = (self.sp as i32, self.stack_bottom as i32);
+        match self.direction {
+            StackDirection::Down => addr >= sp && addr < bottom,
+            StackDirection::Up => addr > bottom && addr <= sp,
+        }
+    }
+
+    // Fetch and decode the instruction at PC
+    fn fetch(&self) -> Result<Instruction, VmError> {
+        let Some(&inst) = self.ram.get(self.pc as usize) else {
+            return Err("(fetch) PC out of bounds".into());
+        };
+        Instruction::decode(inst)
+    }
+
+    // Read a line of input from stdin.
+    //
+    // A line ends at '\n', '\0' or end of input. The terminator is not
+    // included, and a '\r' just before a '\n' is dropped too, so Windows (CRLF)
+    // input reads the same as Unix input.
+    fn read_line(&mut self) -> Result<String, VmError> {
+        let mut s = String::new();
+        let mut buf = [0; 1];
+
+        // Read one byte at a time until newline or null
+        while self.input.read(&mut buf)? > 0 {
+            let c = buf[0] as char;
+            if c != '\0' {
+                self.echo(&buf)?;
+            }
+            if c == '\n' {
+                if s.ends_with('\r') {
+                    s.pop();
+                }
+                break;
+            }
+            if c == '\0' {
+                break;
+            }
+            s.push(c);
+        }
+
+        Ok(s)
+    }
+}
+
+// Wrap trace text in an ANSI color for its opcode's category: control flow,
+// arithmetic, input/output, or stack and memory
+fn paint(opcode: u32, text: &str) -> String {
+    let color = match opcode {
+        0x5..=0x9 => 33,
+        0x2 | 0x3 => 32,
+        0x0 | 0x4 | 0xD | 0xE => 35,
+        _ => 36,
+    };
+    format!("\x1b[{}m{}\x1b[0m", color, text)
+}
+
+// Load and run a program file on the given input, returning its exit code
+// and everything it wrote, without touching the process's stdin or stdout
+pub fn run_capture(program: &[u32], input: &[u8]) -> Result<(u8, Vec<u8>), VmError> {
+    let mut output = Vec::new();
+    let mut machine = Machine::new(input, &mut output, NullHost);
+    machine.load(program)?;
+    let code = machine.run()?;
+    drop(machine);
+    Ok((code, output))
+}
+
+// Process exit status for a signed value given to the stack exit instruction.
+// Statuses are a single byte, so:
+//   0 to 255       that status unchanged
+//   above 255      255
+//   -1 to -128     256 + value (-1 is 255, -2 is 254, ..., -128 is 128), as a
+//                  shell reports exit(-1)
+//   below -128     128
+// A negative value therefore always gives a failing status of 128 or more.
+pub fn exit_status(value: i32) -> u8 {
+    match value {
+        0..=255 => value as u8,
+        256.. => 255,
+        -128..=-1 => (256 + value) as u8,
+        _ => 128,
+    }
+}
+
+// Little-endian words of a binary program or data file
+pub fn bytes_to_words(bytes: &[u8]) -> Result<Vec<u32>, VmError> {
+    if !bytes.len().is_multiple_of(4) {
+        return Err(format!("{} byte(s) is not a whole number of 4-byte words", bytes.len()).into());
+    }
+    Ok(bytes
+        .chunks_exact(4)
+        .map(|chunk| u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect())
+}
+
+// The words of a program file after its header (code and any constant pool)
+pub fn program_code(program: &[u32]) -> Result<&[u32], VmError> {
+    split_header(program).map(|(code, _)| code)
+}
+
+// Split a program file into the words after its header and the length of the
+// constant pool at their end. A version 1 file is the magic word followed by
+// the code; later versions start with VERSIONED_MAGIC and a version word.
+// Version 1 may also be written that way. Version 2 has nothing more,
+// version 3 adds the constant pool's length
+fn split_header(program: &[u32]) -> Result<(&[u32], usize), VmError> {
+    match program {
+        [MAGIC, code @ ..] | [VERSIONED_MAGIC, 1, code @ ..] => Ok((code, 0)),
+        [VERSIONED_MAGIC, 2, code @ ..] => Ok((code, 0)),
+        [VERSIONED_MAGIC, 3, pool_len, code @ ..] if *pool_len as usize <= code.len() => {
+            Ok((code, *pool_len as usize))
+        }
+        [VERSIONED_MAGIC, 3, ..] => Err("constant pool length missing or larger than the program".into()),
+        [VERSIONED_MAGIC, version, ..] => Err(format!("unsupported program version {}", version).into()),
+        [VERSIONED_MAGIC] => Err("missing program version".into()),
+        _ => Err("Bad number".into()),
+    }
+}
+
+// Parse a number typed at an Input prompt into a word.
+//
+// Hex (0x) and binary (0b) inputs are read as raw 32-bit patterns, so values
+// such as 0xFFFFFFFF are accepted; a leading '-' negates them. Decimal input is
+// a signed 32-bit integer.
+fn parse_number(text: &str) -> Result<u32, &'static str> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(rest) if rest.starts_with("0x") || rest.starts_with("0b") => (true, rest),
+        _ => (false, text),
+    };
+
+    let word = if let Some(stripped) = digits.strip_prefix("0x") {
+        u32::from_str_radix(stripped, 16)
+            .map_err(|_| "(input) hex input cannot be converted to an integer")?
+    } else if let Some(stripped) = digits.strip_prefix("0b") {
+        u32::from_str_radix(stripped, 2)
+            .map_err(|_| "(input) binary input cannot be converted to an integer")?
+    } else {
+        digits.parse::<i32>()
+            .map_err(|_| "(input) decimal input cannot be converted to an integer")? as u32
+    };
+
+    Ok(if negative { word.wrapping_neg() } else { word })
+}
+
+// Parse Input text that must be written in one base: 10, 16 (with 0x) or
+// 2 (with 0b). A number in any other base is rejected rather than reread.
+fn parse_number_strict(text: &str, radix: u32) -> Result<u32, &'static str> {
+    let digits = text.strip_prefix('-').unwrap_or(text);
+    let base = if digits.starts_with("0x") {
+        16
+    } else if digits.starts_with("0b") {
+        2
+    } else {
+        10
+    };
+
+    if base != radix {
+        return Err(match radix {
+            16 => "(input) expected a hex (0x) number",
+            2 => "(input) expected a binary (0b) number",
+            _ => "(input) expected a decimal number",
+        });
+    }
+    parse_number(text)
+}
@@ -3,616 +3,445 @@
  * Synopsis: Executes 4-byte instructions in an assembled binary file
  */
 
-use std::io::{self, Read, Write};
+use std::io::{self, BufWriter, IsTerminal, Stdin, Stdout};
+use std::any::Any;
 use std::env::args;
 use std::fs;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
+
+use vmproject::{bytes_to_words, disassemble, disassemble_raw, find_dead_code, parse_binary_trace, parse_trace, program_code, verify_branches, FlushPolicy, FrameCheck, Machine, MachineState, NullHost, StackDirection, TraceFormat, VmError, WordWidth};
 
 fn main() {
     // Check arguments
     let args: Vec<String> = args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} <file.v>", &args[0]);
+    if let [_, command, path] = &args[..] && command == "dump-trace" {
+        dump_trace(path);
         return;
     }
-    let filename = &args[1];
-
-    let binary = fs::read(filename).expect("No such file or directory");
-
-    // Convert the binary data into a vector of u32 instructions
-    let program: Vec<u32> = binary
-        .chunks(4)                                      
-        .map(|chunk| u32::from_le_bytes(chunk.try_into().unwrap()))  
-        .collect();
-
-    // Create a new virtual machine instance
-    let mut machine = Machine {
-        ram: [0; 1024],     
-        sp: 1024,            
-        pc: 0,               
-        input: io::stdin(),  
-        output: io::stdout() 
-    };
-
-    // Load the program into the VM's memory
-    machine.load(&program).unwrap();
-
-    // Run the program and exit with its return code
-    let exit_code = machine.run().unwrap();
-    std::process::exit(exit_code.into());
-}
-
-// Virtual Machine structure, parameterized over input/output types (for testing flexibility)
-struct Machine<R: Read, W: Write> {
-    ram: [u32; 1024], 
-    sp: i16,         
-    pc: i16,         
-    input: R,         
-    output: W,        
-}
 
-// Instruction set (interpreted from RAM contents)
-#[derive(Debug)]
-enum Instruction {
-    // Miscellaneous
-    Exit(u8),        
-    Swap(i16, i16),   
-    Nop(),           
-    Input(),          
-    Stinput(u32),    
-    Debug(u32),       
-
-    Pop(u32),         
-
-    // Binary Arithmetic
-    Add(),
-    Subtract(),
-    Multiply(),
-    Divide(),
-    Remainder(),
-    And(),
-    Or(),
-    Xor(),
-    LogicalLeftShift(),
-    LogicalRightShift(),
-    ArithmeticRightShift(),
-
-    // Unary Arithmetic
-    Negate(),
-    Not(),
-
-    Stprint(i32),     
-
-    Goto(i32),
-
-    Call(i32),
-    Return(u32),
-
-    BinaryIf(u8, i32),
-
-    // Unary If
-    EqZero(i32),      
-    NeZero(i32),      
-    LtZero(i32),      
-    GeZero(i32),     
-    Dup(u32),
-    Dump(),
-    Print(i32),
-
-    Push(u32),        
-}
-
-// Top-level instruction class based on opcode nibble
-#[derive(Debug)]
-enum Opcode {
-    Miscellaneous, 
-    BinaryArithmetic,
-    UnaryArithmetic,
-    Pop,          
-    StringPrint,   
-    Goto,
-    Call,
-    Return,
-    UnaryIf,     
-    BinaryIf,
-    Dup,
-    Print,
-    Dump,
-    Push,          
-    Unknown,       
-}
+    // A missing program or a bad option is a usage error
+    let Some(options) = Options::parse(&args[1..]) else {
+        eprintln!("Usage: {} [options] <file.v> [--] [program arguments]", &args[0]);
+        eprintln!("       {} [options] --resume STATE", &args[0]);
+        eprintln!("       {} dump-trace TRACE", &args[0]);
+        eprintln!("  --ram-dump PATH    write RAM to PATH when the program ends");
+        eprintln!("  --check-frames     warn when a subroutine returns with an unbalanced stack");
+        eprintln!("  --strict-frames    treat an unbalanced subroutine return as an error");
+        eprintln!("  --stack-up         grow the stack upward from the end of the program");
+        eprintln!("  --max-cycles N     stop with an error after N instructions");
+        eprintln!("  --verbose          describe the loaded program on stderr before running");
+        eprintln!("  --guard-stack      stop with an error if PC reaches live stack data");
+        eprintln!("  --save-state PATH  write the machine state to PATH when the run stops");
+        eprintln!("  --resume STATE     continue from a state written by --save-state");
+        eprintln!("  --trace            trace each instruction on stderr");
+        eprintln!("  --trace-format=F   trace as 'human' (default) or 'jsonl' lines, or 'binary' records (needs --trace-file)");
+        eprintln!("  --trace-file PATH  write the trace to PATH instead of stderr");
+        eprintln!("  --trace-sample N   trace only every Nth instruction (implies --trace)");
+        eprintln!("  --no-color         never color the human trace (it is colored only on a terminal)");
+        eprintln!("  --entry N          start execution at word address N");
+        eprintln!("  --initial-sp N     start with SP at N instead of the end of RAM, reserving the words above it");
+        eprintln!("  --hex              read the program as ASCII hex words (implied by .vhex)");
+        eprintln!("  --diff-trace PATH  stop at the first step whose PC/SP differ from a JSONL trace");
+        eprintln!("  --disassemble      list the program with labelled branch targets instead of running it");
+        eprintln!("  --find-dead-code   list the words no path from the entry point reaches, instead of running");
+        eprintln!("  --raw              show each instruction's raw word in the --disassemble listing");
+        eprintln!("  --print-exit       write the program's exit code to stderr");
+        eprintln!("  --seed N           seed the rand instruction's generator");
+        eprintln!("  --step-count N     run at most N instructions, then show PC, SP and the stack on stderr");
+        eprintln!("  --run-until N      run until PC reaches word address N, then show PC, SP and the stack on stderr");
+        eprintln!("  --flush=P          flush output 'always' (default), per 'line', or 'onexit'");
+        eprintln!("  --word16           do arithmetic on 16-bit values instead of 32-bit");
+        eprintln!("  --stats            summarize the run on stderr when the program stops");
+        eprintln!("  --trap-null-write  stop with an error if a store or memcopy writes to address 0");
+        eprintln!("  --strict           stop with an error if a branch targets an address outside the program");
+        eprintln!("  --verify           before running, check that every branch target is a whole word inside the code");
+        eprintln!("  --max-output N     stop with an error after N bytes of program output");
+        eprintln!("  --data PATH        load a binary data file (no magic word) into RAM after the program");
+        eprintln!("  --data-addr N      word address for --data (default: just past the program)");
+        eprintln!("  --echo-input       copy the input the program reads to its output");
+        eprintln!("  --quiet            no traces, stats, warnings or debug output; only the program's own output");
+        process::exit(2);
+    };
 
-impl Opcode {
-
-    // Convert the top 4 bits (opcode) of an instruction to an `Opcode` enum
-    fn from_integer(n: u8) -> Opcode {
-        match n {
-            0x0 => Opcode::Miscellaneous,
-            0x1 => Opcode::Pop,
-            0x2 => Opcode::BinaryArithmetic,
-            0x3 => Opcode::UnaryArithmetic,
-            0x4 => Opcode::StringPrint,
-            0x5 => Opcode::Call,
-            0x6 => Opcode::Return,
-            0x7 => Opcode::Goto,
-            0x8 => Opcode::BinaryIf,
-            0x9 => Opcode::UnaryIf,
-            0xC => Opcode::Dup,
-            0xD => Opcode::Print,
-            0xE => Opcode::Dump,
-            0xF => Opcode::Push,
-            _ => Opcode::Unknown,
-        }
+    // Binary records would be unreadable mixed into the terminal's stderr
+    if options.trace == Some(TraceFormat::Binary) && options.trace_file.is_none() {
+        fail("--trace-format=binary needs --trace-file");
     }
-}
 
-impl<R: Read, W: Write> Machine<R, W> {
-
-    // Load a program into RAM, checking for magic number
-    pub fn load(&mut self, program: &[u32]) -> Result<(), &'static str> {
-        if program.first() != Some(&0xEFBE_ADDE) {
-            return Err("Bad number"); 
+    // Create a new virtual machine instance, either from a saved state or a program
+    let mut machine = match &options.resume {
+        Some(path) => {
+            let bytes = fs::read(path).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+            let state = MachineState::from_bytes(&bytes).unwrap_or_else(|e| fail(e));
+            Machine::from_state(state, io::stdin(), BufWriter::new(io::stdout()), NullHost)
         }
-
-        // Load the program (skipping the magic word) into RAM
-        self.ram[..program.len() - 1].copy_from_slice(&program[1..]);
-        self.sp = 1024;
-        self.pc = 0;
-
-        Ok(())
-    }
-
-    // Run the virtual machine loop
-    pub fn run(&mut self) -> Result<u8, Box<dyn std::error::Error>> {
-        loop {
-            let instruction = self.fetch(); 
-
-            match instruction {
-                Instruction::Exit(code) => return Ok(code),
-
-                Instruction::Swap(from, to) => {
-                    // Sign-extend the 12-bit offsets
-                    let from_offset = (((from as i16) << 4) >> 2) as i16;
-                    let to_offset = (((to as i16) << 4) >> 2) as i16;
-
-                    // Swap two words in the stack (from and to are relative to SP)
-                    let f = (self.sp + (from_offset >> 2)) as usize;
-                    let t = (self.sp + (to_offset >> 2)) as usize;
-                    self.ram.swap(f, t);
-                }
-
-                Instruction::Nop() => (), 
-
-                Instruction::Input() => {
-                    // Read a number (decimal/hex/bin) from user
-                    let line = self.read_line()?;
-                    let trimmed = line.trim();
-
-                    let word = if let Some(stripped) = trimmed.strip_prefix("0x") {
-                        i32::from_str_radix(stripped, 16)
-                            .map_err(|_| "(input) hex input cannot be converted to an integer")
-                    } else if let Some(stripped) = trimmed.strip_prefix("0b") {
-                        i32::from_str_radix(stripped, 2)
-                            .map_err(|_| "(input) binary input cannotbe converted to an integer")
-                    } else {
-                        i32::from_str_radix(trimmed, 10)
-                            .map_err(|_| "(input) decimal input cannot be converted to an integer")
-                    }?;
-
-                    self.push(word as u32)?;
-                }
-
-                Instruction::Stinput(max_chars) => {
-
-                    // Read a string from input and store it in RAM using 24-bit packing
-                    let mut input = self.read_line()?.trim().to_string();
-                    if input.is_empty() {
-                        self.push(0)?;
-                        continue;
-                    }
-
-                    input.truncate(max_chars as usize);
-
-                    // Pad to 3-byte alignment with sentinel value 0x01
-                    while input.len() % 3 != 0 {
-                        input.push(0x01 as char);
-                    }
-
-                    // Encode string backwards into stack
-                    for (i, chunk) in input.as_bytes().rchunks(3).enumerate() {
-                        let word = ((chunk[2] as u32) << 16)
-                            | ((chunk[1] as u32) << 8)
-                            | (chunk[0] as u32)
-                            | if i != 0 { 0x0100_0000 } else { 0 };
-
-                        self.push(word)?;
-                    }
-                }
-
-                Instruction::Debug(_offset) => {
-                    println!("Debug");
-                }
-
-                /*
-                 * Binary Arithmetic Instructions
-                 */
-                Instruction::Add()                  => self.binary_op(|l, r| l + r),
-                Instruction::Subtract()             => self.binary_op(|l, r| l - r),
-                Instruction::Multiply()             => self.binary_op(|l, r| l * r),
-                Instruction::Divide()               => self.binary_op(|l, r| l / r),
-                Instruction::Remainder()            => self.binary_op(|l, r| l % r),
-                Instruction::And()                  => self.binary_op(|l, r| l & r),
-                Instruction::Or()                   => self.binary_op(|l, r| l | r),
-                Instruction::Xor()                  => self.binary_op(|l, r| l ^ r),
-                Instruction::LogicalLeftShift()     => self.binary_op(|l, r| l << r),
-                Instruction::LogicalRightShift()    => self.binary_op(|l, r| l >> r),
-                Instruction::ArithmeticRightShift() => self.binary_op(|l, r| l as i32 >> r),
-
-                /*
-                 * Unary Arithmetic Instructions
-                 */
-                Instruction::Not() => self.unary_op(|x| !x),
-                Instruction::Negate() => self.unary_op(|x| x.wrapping_neg()),
-
-                Instruction::Pop(offset) => {
-
-                    // Pop offset bytes (in 4-byte words) from the stack
-                    self.sp = (self.sp + (offset >> 2) as i16).clamp(0, 1024);
-                }
-
-                Instruction::Goto(offset) => {
-                    self.pc += offset as i16;
-                    continue;
-                }
-
-                Instruction::Stprint(offset) => {
-
-                    // Print a packed string from RAM starting at offset
-                    let mut idx = (self.sp + (offset >> 2) as i16) as usize;
-                    loop {
-                        let cur_word = self.ram[idx];
-
-                        if cur_word == 0 {
-                            break;
-                        }
-
-                        let bytes = cur_word.to_le_bytes(); 
-                        for &b in &bytes {
-                            if b != 1 {
-                                self.output.write_all(&[b])?;
-                            }
-                        }
-                        if bytes[3] == 0 || idx == 0 {
-                            break;
-                        }
-                        idx += 1;
-                    }
-
-                    self.output.flush()?;
-                }
-
-
-                Instruction::Call(offset) => {
-                    // Push return address (next PC)
-                    let return_address = (self.pc + 1) as u32;
-                    self.push(return_address)?;
-
-                    // Jump to offset
-                    self.pc += offset as i16;
-                    continue;
-                }
-
-                Instruction::Return(offset) => {
-                    // Calculate address location using offset
-                    let offset_words = (offset / 4) as i16;
-                    let addr_index = (self.sp + offset_words) as usize;
-
-                    // Pop the return address
-                    let addr = self.ram.get(addr_index).copied().unwrap_or(0);
-
-                    // Free the frame
-                    self.sp += 1 + offset_words;
-
-                    self.pc = addr as i16;
-                    continue;
-                }
-
-                /*
-                 * Binary If Instructions
-                 */
-                Instruction::BinaryIf(cond, offset) => {
-                    let right = *self.ram.get(self.sp as usize).unwrap_or(&0);
-                    let left = *self.ram.get((self.sp + 1) as usize).unwrap_or(&0);
-
-                    let taken = match cond {
-                        0 => left == right,
-                        1 => left != right,
-                        2 => left < right,
-                        3 => left > right,
-                        4 => left <= right,
-                        5 => left >= right,
-                        _ => false,
-                    };
-                    if taken {
-                        self.pc += offset as i16;
-                        continue;
-                    }
+        None => {
+            let filename = options.filename.as_ref().unwrap();
+            let binary = fs::read(filename).unwrap_or_else(|e| fail(format!("{}: {}", filename, e)));
+
+            // Convert the file into a vector of u32 instructions
+            let program = if options.hex || filename.ends_with(".vhex") {
+                let text = String::from_utf8_lossy(&binary);
+                parse_hex_program(&text).unwrap_or_else(|e| fail(format!("{}: {}", filename, e)))
+            } else {
+                bytes_to_words(&binary).unwrap_or_else(|e| fail(format!("{}: {}", filename, e)))
+            };
+
+            if options.disassemble {
+                match program_code(&program) {
+                    Ok(code) if options.raw => print!("{}", disassemble_raw(code)),
+                    Ok(code) => print!("{}", disassemble(code)),
+                    Err(e) => fail(e),
                 }
+                return;
+            }
 
-                /*
-                 * Unary If Instructions
-                 */
-                Instruction::EqZero(offset) => {
-                    if self.unary_if(offset, |x| x == 0) {
-                        continue;
-                    }
-                }
-                Instruction::NeZero(offset) => {
-                    if self.unary_if(offset, |x| x != 0) {
-                        continue;
-                    }
+            if options.find_dead_code {
+                let code = program_code(&program).unwrap_or_else(|e| fail(e));
+                let dead = find_dead_code(code, options.entry);
+                if dead.is_empty() {
+                    println!("no dead code");
                 }
-                Instruction::GeZero(offset) => {
-                    if self.unary_if(offset, |x| x >= 0) {
-                        continue;
-                    }
-                }
-                Instruction::LtZero(offset) => {
-                    if self.unary_if(offset, |x| x < 0) {
-                        continue;
-                    }
+                for addr in dead {
+                    println!("    {:04x}: {:08x}  unreachable", addr, code[addr]);
                 }
+                return;
+            }
 
-                Instruction::Dup(offset) => {
-                    let idx = (self.sp + (offset >> 2) as i16) as usize;
-                    let val = self.ram[idx];
-                    self.push(val)?;
-                }
+            if options.verify {
+                verify_branches(&program).unwrap_or_else(|e| fail(format!("{}: {}", filename, e)));
+            }
 
-                Instruction::Print(offset) => {
-                    let idx = (self.sp + (offset >> 2) as i16) as usize;
-                    let val = self.ram[idx];
-
-                    match offset & 0b11 {
-                        0b00 => writeln!(self.output, "{}", val as i32)?,
-                        0b01 => writeln!(self.output, "{:#x}", val as i32)?,
-                        0b10 => writeln!(self.output, "{:#b}", val as i32)?,
-                        0b11 => writeln!(self.output, "{:#o}", val as i32)?,
-                        _ => unreachable!(),
-                    }
-                    self.output.flush()?;
-                },
-
-                Instruction::Dump() => {
-                    if self.sp == 1024 {
-                        // stack empty (nop)
-
-                    } else {
-                        for offset in self.sp..1024 {
-                            let address = offset - self.sp;
-                            let value = self.ram[offset as usize];
-                            writeln!(self.output, "{:04x}: {:08x}", address, value)?;
-                        }
-
-                        self.output.flush()?;
-                    }
+            // Load the program into the VM's memory
+            let mut machine = Machine::new(io::stdin(), BufWriter::new(io::stdout()), NullHost);
+            machine.direction = options.direction;
+            machine.entry = options.entry;
+            machine.initial_sp = options.initial_sp;
+            machine.load(&program).unwrap_or_else(|e| fail(e));
+
+            // Place a separate data file after the code
+            if let Some(path) = &options.data {
+                let bytes = fs::read(path).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+                let data = bytes_to_words(&bytes).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+                let addr = options.data_addr.unwrap_or_else(|| program_code(&program).map_or(0, |code| code.len()));
+                machine.load_data(addr, &data).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+            }
+            machine
+        }
+    };
+    machine.frame_check = options.frame_check;
+    machine.max_cycles = options.max_cycles;
+    machine.guard_stack = options.guard_stack;
+    machine.trace = options.trace;
+    machine.trace_sample = options.trace_sample;
+    machine.color = !options.no_color && io::stderr().is_terminal();
+    if let Some(path) = &options.trace_file {
+        let file = fs::File::create(path).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+        machine.trace_output = Some(Box::new(BufWriter::new(file)));
+    }
+    machine.flush = options.flush;
+    // A resumed machine keeps the word width it was saved with unless --word16 is given
+    if options.word_width != WordWidth::default() {
+        machine.word_width = options.word_width;
+    }
+    machine.strict_branches = options.strict;
+    machine.trap_null_write = options.trap_null_write;
+    machine.max_output = options.max_output;
+    machine.echo_input = options.echo_input;
+    machine.interactive = io::stdin().is_terminal();
+    machine.set_args(&options.args);
+    machine.quiet = options.quiet;
+    if let Some(seed) = options.seed {
+        machine.seed_rng(seed);
+    }
 
-                }
+    if options.verbose && !machine.quiet {
+        eprint!("{}", machine.describe());
+    }
 
+    // Run the program, optionally checking each step against a reference trace.
+    // A panic inside the VM is a bug, reported briefly instead of as a Rust backtrace
+    let result = catch_panic(|| match (&options.diff_trace, options.step_count, options.run_until) {
+        (Some(path), _, _) => {
+            let text = fs::read_to_string(path).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+            let trace = parse_trace(&text).unwrap_or_else(|e| fail(e));
+            machine.run_against(&trace)
+        }
+        (None, Some(steps), _) => run_steps(&mut machine, steps),
+        (None, None, Some(target)) => run_to(&mut machine, target),
+        (None, None, None) => machine.run(),
+    });
+    let result = result.unwrap_or_else(|payload| {
+        let _ = machine.flush();
+        let (message, status) = internal_error(payload);
+        eprintln!("{}", message);
+        process::exit(status);
+    });
+
+    // Show any output the flush policy held back, whether or not the run succeeded
+    let result = machine.flush().and(result);
+
+    // Write RAM out for post-mortem analysis, whether or not the run succeeded
+    if let Some(path) = &options.ram_dump {
+        let bytes: Vec<u8> = machine.ram().iter().flat_map(|word| word.to_le_bytes()).collect();
+        fs::write(path, bytes).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+    }
 
-                Instruction::Push(val) => self.push(val)?, 
-            }
+    // Checkpoint the machine so a later run can --resume it
+    if let Some(path) = &options.save_state {
+        fs::write(path, machine.snapshot().to_bytes()).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+    }
 
-            self.step(); 
+    // Summarize the run, whether or not it succeeded; then exit with the program's return code
+    if options.stats && !machine.quiet {
+        let stats = machine.stats();
+        eprintln!("cycles: {}", stats.cycles);
+        eprintln!("peak stack depth: {}", stats.peak_depth);
+        eprintln!("calls: {}", stats.calls);
+        eprintln!("returns: {}", stats.returns);
+        eprintln!("peak call depth: {}", stats.peak_call_depth);
+        eprintln!("output bytes: {}", stats.output_bytes);
+    }
+    let exit_code = result.unwrap_or_else(|e| {
+        let mut message = e.to_string();
+        let recent = machine.recent_instructions();
+        if !recent.is_empty() && !machine.quiet {
+            message += &format!("\nrecent instructions:\n{}", recent.trim_end());
         }
+        fail(message)
+    });
+    if options.print_exit && !machine.quiet {
+        eprintln!("exit code: {}", exit_code);
     }
+    process::exit(exit_code.into());
+}
 
-    /*
-     * Binary arithmetic helper function
-     */
-    fn binary_op<F>(&mut self, op: F)
-    where
-        F: Fn(i32, i32) -> i32,
-        {
-            // Get right operand
-            let right = self.ram[self.sp as usize] as i32;
-            self.sp += 1;
-
-            // Get left operand
-            let left = self.ram[self.sp as usize] as i32;
-            self.sp += 1;
-
-            // Apply binary operation to operands
-            let result = op(left, right);
-
-            self.sp -= 1;
-            self.ram[self.sp as usize] = result as u32;
-        }
+// Execute up to `steps` instructions (stopping early at Exit), then describe
+// the machine on stderr. Returns the exit code, or 0 if the program is still running.
+fn run_steps(machine: &mut Machine<Stdin, BufWriter<Stdout>>, steps: u64) -> Result<u8, VmError> {
+    let mut taken = 0;
+    let mut exit_code = None;
+    while taken < steps && exit_code.is_none() {
+        exit_code = machine.step_instruction()?;
+        taken += 1;
+    }
 
-    /*
-     * Unary arithmetic helper function
-     */
-    fn unary_op<F>(&mut self, op: F)
-    where
-        F: Fn(i32) -> i32,
-        {
-            let val = self.ram[self.sp as usize] as i32;
-            self.sp += 1;
-
-            let result = op(val);
-            self.sp -= 1;
-            self.ram[self.sp as usize] = result as u32;
-        }
+    show_stop(machine, &format!("after {} step(s)", taken));
+    Ok(exit_code.unwrap_or(0))
+}
 
-    /*
-     * Unary If helper function
-     */
-    fn unary_if<F>(&mut self, offset: i32, cond: F) -> bool
-    where
-        F: Fn(i32) -> bool,
-        {
-            let val = self.ram[self.sp as usize] as i32;
-            if cond(val) {
-                self.pc += (offset >> 2) as i16;
-                return true; // Jump occurred
-            }
-            false
+// Run until PC reaches `target` or the program exits, then describe the
+// machine on stderr. Returns the exit code, or 0 if it stopped at the target.
+fn run_to(machine: &mut Machine<Stdin, BufWriter<Stdout>>, target: i16) -> Result<u8, VmError> {
+    let exit_code = machine.run_until(target)?;
+    match exit_code {
+        Some(code) => show_stop(machine, &format!("exited with {} before reaching {}", code, target)),
+        None => show_stop(machine, &format!("reached {}", target)),
+    }
+    Ok(exit_code.unwrap_or(0))
+}
+
+// Describe where a partial run stopped: PC, SP and the stack, top first
+fn show_stop(machine: &Machine<Stdin, BufWriter<Stdout>>, heading: &str) {
+    if !machine.quiet {
+        eprintln!("{}: pc={} sp={}", heading, machine.pc(), machine.sp());
+        for (offset, value) in machine.stack().iter().enumerate() {
+            eprintln!("  {:04x}: {:08x}", offset, value);
         }
+    }
+}
 
-    // Increment program counter
-    fn step(&mut self) {
-        self.pc += 1;
+// Print each record of a binary trace as a line of text
+fn dump_trace(path: &str) {
+    let bytes = fs::read(path).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+    let records = parse_binary_trace(&bytes).unwrap_or_else(|e| fail(format!("{}: {}", path, e)));
+    for record in records {
+        println!("{}", record);
     }
+}
 
-    // Push a value onto the stack
-    fn push(&mut self, word: u32) -> Result<(), Box<dyn std::error::Error>> {
-        if self.sp <= 0 {
-            return Err("Overflow".into());
+// Parse a program written as whitespace-separated hex words, one word per
+// token with an optional 0x prefix, starting with the magic word efbeadde.
+// Everything after a '#' on a line is a comment.
+fn parse_hex_program(text: &str) -> Result<Vec<u32>, String> {
+    let mut program = Vec::new();
+
+    for (number, line) in text.lines().enumerate() {
+        let code = line.split('#').next().unwrap_or("");
+        for token in code.split_whitespace() {
+            let digits = token.strip_prefix("0x").unwrap_or(token);
+            let word = u32::from_str_radix(digits, 16)
+                .map_err(|_| format!("line {}: '{}' is not a hex word", number + 1, token))?;
+            program.push(word);
         }
-        self.sp -= 1;
-        self.ram[self.sp as usize] = word;
-        Ok(())
     }
 
-    // Decode an instruction from RAM
-    fn fetch(&self) -> Instruction {
-        let inst = self.ram[self.pc as usize];
-        let opcode = Opcode::from_integer(((inst >> 28) & 0xF) as u8);
-
-        use Instruction::*;
-        match opcode {
-            Opcode::Miscellaneous => match (inst >> 24) & 0xF {
-                0x0 => Exit(inst as u8 & 0xFF),
-                0x1 => Swap((inst >> 12) as i16 & 0xFFF, inst as i16 & 0xFFF),
-                0x2 => Nop(),
-                0x4 => Input(),
-                0x5 => Stinput(inst & 0xFFFFFF),
-                0xF => Debug(inst & 0xFFFFFF),
-                _ => panic!("Invalid Miscellaneous Instruction"),
-            },
-
-            Opcode::BinaryArithmetic => match (inst >> 24) & 0xF {
-                0x0 => Add(),
-                0x1 => Subtract(),
-                0x2 => Multiply(),
-                0x3 => Divide(),
-                0x4 => Remainder(),
-                0x5 => And(),
-                0x6 => Or(),
-                0x7 => Xor(),
-                0x8 => LogicalLeftShift(),
-                0x9 => LogicalRightShift(),
-                0xB => ArithmeticRightShift(),
-                _ => panic!("Invalid Binary Arithmetic Instruction"),
-            },
-
-
-            Opcode::UnaryArithmetic => match (inst >> 24) & 0xF {
-                0x0 => Negate(),
-                0x1 => Not(),
-                _ => panic!("Invalid Unary Arithmetic Instruction"),
-            },
-
-            Opcode::Pop => Pop(inst & 0x0FFF_FFFF),
-
-            Opcode::Goto => {
-                // Extract offset
-                let raw = (inst >> 2) & 0x03FF_FFFF;
-
-                let offset = if (raw & (1 << 25)) != 0 {
-                    // Sign extend negative offset
-                    (raw | !0x03FF_FFFF) as i32
-                } else {
-                    raw as i32
-                };
-                Goto(offset)
-            },
-
-            Opcode::StringPrint => Stprint(inst as i32 & 0x0FFF_FFFF),
-
-            Opcode::Call => {
-                // Extract offset
-                let raw = (inst >> 2) & 0x03FF_FFFF;
-
-                let offset = if (raw & (1 << 25)) != 0 {
-                    // Sign extend negative offset
-                    (raw | !0x03FF_FFFF) as i32
-                } else {
-                    raw as i32
-                };
-                Call(offset)
-            },
-            Opcode::Return => {
-                let offset = inst & 0x0FFF_FFFF;
-                Return(offset as u32)
-            }
+    Ok(program)
+}
 
-            Opcode::BinaryIf => {
-                let cond = (inst >> 25) & 0b111;
-                let raw = (inst >> 2) & 0x007F_FFFF;
-                let offset = if raw & (1 << 22) != 0 {
-                    (raw as i32) | !0x007F_FFFF
-                } else {
-                    raw as i32
-                };
-                BinaryIf(cond as u8, offset as i32)
-            }
+// Report an error on stderr and stop with a nonzero status
+fn fail(message: impl std::fmt::Display) -> ! {
+    eprintln!("Error: {}", message);
+    process::exit(1);
+}
 
-            Opcode::UnaryIf => {
-
-                // Branching instruction (e.g. EqZero, NeZero, etc.)
-                let func2 = (inst >> 25) & 0b11;
-                let offset = {
-                    let mut val = inst as i32 & 0x00FF_FFFF;
-                    if val >> 23 == 1 {
-
-                        // Sign extend negative values
-                        val |= 0xFF00_0000u32 as i32;
-                    }
-                    val
-                };
-                match func2 {
-                    0b00 => EqZero(offset),
-                    0b01 => NeZero(offset),
-                    0b10 => LtZero(offset),
-                    0b11 => GeZero(offset),
-                    _ => unreachable!(),
-                }
-            }
-            Opcode::Dup => {
-                let offset = inst & 0x0FFF_FFFF;
-                Dup(offset) 
-            }
+// Run `run`, returning the payload of any panic instead of printing it
+fn catch_panic<T>(run: impl FnOnce() -> T) -> Result<T, Box<dyn Any + Send>> {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| ()));
+    let result = panic::catch_unwind(AssertUnwindSafe(run));
+    panic::set_hook(default_hook);
+    result
+}
 
-            Opcode::Print => Print(inst as i32 & 0x0FFF_FFFF),
-            Opcode::Dump => Dump(),
-            Opcode::Push => {
+// The report and exit status (2) for a panic caught from the VM
+fn internal_error(payload: Box<dyn Any + Send>) -> (String, i32) {
+    let detail = match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(message), _) => message.to_string(),
+        (_, Some(message)) => message.clone(),
+        _ => String::from("unknown panic"),
+    };
+    (format!("Error: internal VM error ({})", detail), 2)
+}
+
+// Command-line options
+#[derive(Default)]
+struct Options {
+    filename: Option<String>,
+    ram_dump: Option<String>,
+    frame_check: FrameCheck,
+    direction: StackDirection,
+    max_cycles: Option<u64>,
+    verbose: bool,
+    guard_stack: bool,
+    save_state: Option<String>,
+    resume: Option<String>,
+    trace: Option<TraceFormat>,
+    entry: usize,
+    hex: bool,
+    diff_trace: Option<String>,
+    disassemble: bool,
+    print_exit: bool,
+    seed: Option<u64>,
+    step_count: Option<u64>,
+    flush: FlushPolicy,
+    trace_file: Option<String>,
+    word_width: WordWidth,
+    stats: bool,
+    strict: bool,
+    max_output: Option<u64>,
+    data: Option<String>,
+    data_addr: Option<usize>,
+    echo_input: bool,
+    trace_sample: Option<u64>,
+    raw: bool,
+    args: Vec<String>,
+    quiet: bool,
+    no_color: bool,
+    run_until: Option<i16>,
+    find_dead_code: bool,
+    verify: bool,
+    initial_sp: Option<i16>,
+    trap_null_write: bool,
+}
 
-                // Push a signed immediate value
-                let mut val = inst & 0x0FFF_FFFF;
-                if (val >> 27) == 1 {
-                    val |= 0xF000_0000;
+impl Options {
+
+    // Parse the arguments following the program name, or None if they are malformed
+    fn parse(args: &[String]) -> Option<Options> {
+        let mut options = Options::default();
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--ram-dump" => options.ram_dump = Some(args.next()?.clone()),
+                "--check-frames" => options.frame_check = FrameCheck::Warn,
+                "--strict-frames" => options.frame_check = FrameCheck::Strict,
+                "--stack-up" => options.direction = StackDirection::Up,
+                "--max-cycles" => options.max_cycles = Some(args.next()?.parse().ok()?),
+                "--verbose" => options.verbose = true,
+                "--guard-stack" => options.guard_stack = true,
+                "--save-state" => options.save_state = Some(args.next()?.clone()),
+                "--resume" => options.resume = Some(args.next()?.clone()),
+                "--trace" => options.trace = options.trace.or(Some(TraceFormat::Human)),
+                "--trace-format=human" => options.trace = Some(TraceFormat::Human),
+                "--trace-format=jsonl" => options.trace = Some(TraceFormat::Jsonl),
+                "--trace-format=binary" => options.trace = Some(TraceFormat::Binary),
+                "--trace-sample" => {
+                    options.trace_sample = Some(args.next()?.parse().ok().filter(|&every| every > 0)?);
+                    options.trace = options.trace.or(Some(TraceFormat::Human));
                 }
-                Push(val)
+                "--trace-file" => options.trace_file = Some(args.next()?.clone()),
+                "--word16" => options.word_width = WordWidth::Bits16,
+                "--stats" => options.stats = true,
+                "--trap-null-write" => options.trap_null_write = true,
+                "--strict" => options.strict = true,
+                "--verify" => options.verify = true,
+                "--max-output" => options.max_output = Some(args.next()?.parse().ok()?),
+                "--data" => options.data = Some(args.next()?.clone()),
+                "--data-addr" => options.data_addr = Some(args.next()?.parse().ok()?),
+                "--no-color" => options.no_color = true,
+                "--quiet" => options.quiet = true,
+                "--echo-input" => options.echo_input = true,
+                "--initial-sp" => options.initial_sp = Some(args.next()?.parse().ok()?),
+                "--entry" => options.entry = args.next()?.parse().ok()?,
+                "--hex" => options.hex = true,
+                "--diff-trace" => options.diff_trace = Some(args.next()?.clone()),
+                "--disassemble" => options.disassemble = true,
+                "--find-dead-code" => options.find_dead_code = true,
+                "--raw" => options.raw = true,
+                "--print-exit" => options.print_exit = true,
+                "--seed" => options.seed = Some(args.next()?.parse().ok()?),
+                "--run-until" => options.run_until = Some(args.next()?.parse().ok()?),
+                "--step-count" => options.step_count = Some(args.next()?.parse().ok()?),
+                "--flush=always" => options.flush = FlushPolicy::Always,
+                "--flush=line" => options.flush = FlushPolicy::Line,
+                "--flush=onexit" => options.flush = FlushPolicy::OnExit,
+                "--" if options.filename.is_some() => options.args.extend(args.by_ref().cloned()),
+                _ if options.filename.is_none() && !arg.starts_with("--") => options.filename = Some(arg.clone()),
+
+                // Anything else after the program is an argument for it
+                _ if !arg.starts_with("--") => options.args.push(arg.clone()),
+                _ => return None,
             }
+        }
 
-            _ => panic!("Unimplemented opcode"),
+        // Need exactly one of a program or a saved state
+        if options.filename.is_some() == options.resume.is_some() {
+            return None;
         }
+        Some(options)
     }
+}
 
-    // Read a line of input from stdin
-    fn read_line(&mut self) -> Result<String, Box<dyn std::error::Error>> {
-        let mut s = String::new();
-        let mut buf = [0; 1];
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-        // Read one byte at a time until newline or null
-        while self.input.read(&mut buf).map_err(|_| "IO error")? > 0 {
-            let c = buf[0] as char;
-            if c == '\n' || c == '\0' {
-                break;
-            }
-            s.push(c);
-        }
+    #[test]
+    fn panic_in_the_vm_is_reported_as_an_internal_error() {
+        let mut machine = Machine::with_input_lines(&[], Vec::new());
+        machine.load(&[0xEFBE_ADDE, 0x0000_0000]).unwrap();
+        machine.set_hook(Box::new(|_, _| panic!("hook failed")));
+
+        let payload = catch_panic(|| machine.run()).unwrap_err();
+        assert_eq!(internal_error(payload), (String::from("Error: internal VM error (hook failed)"), 2));
+    }
+
+    #[test]
+    fn hex_program_loads_and_runs() {
+        let text = "efbeadde  # magic\n0xf0000007 d0000000\n\n00000003  # exit 3\n";
+        let program = parse_hex_program(text).unwrap();
+        assert_eq!(program, [0xEFBE_ADDE, 0xF000_0007, 0xD000_0000, 0x0000_0003]);
+
+        let mut machine = Machine::with_input_lines(&[], Vec::new());
+        machine.load(&program).unwrap();
+        assert_eq!(machine.run().unwrap(), 3);
+    }
 
-        Ok(s)
+    #[test]
+    fn hex_program_names_a_bad_token() {
+        assert_eq!(parse_hex_program("efbeadde\nf000000g\n"), Err(String::from("line 2: 'f000000g' is not a hex word")));
     }
 }
@@ -0,0 +1,1831 @@
+/* Synopsis: Unit tests for the machine
+ *
+ * Programs are written as instruction words (the magic word is added by
+ * `program`) and run on a machine reading preset input lines and writing
+ * to a byte buffer, so each test checks the exit code, the stack and the
+ * exact output.
+ */
+
+use std::cell::RefCell;
+use std::io::Cursor;
+use std::rc::Rc;
+
+use super::*;
+
+type TestMachine = Machine<Cursor<Vec<u8>>, Vec<u8>>;
+
+// A version 1 program file holding `code`
+fn program(code: &[u32]) -> Vec<u32> {
+    [MAGIC].iter().chain(code).copied().collect()
+}
+
+// A machine with `code` loaded, reading `lines` as its input
+fn machine(code: &[u32], lines: &[&str]) -> TestMachine {
+    let mut machine = Machine::with_input_lines(lines, Vec::new());
+    machine.load(&program(code)).unwrap();
+    machine
+}
+
+// Everything the program has written so far
+fn output(machine: &TestMachine) -> String {
+    String::from_utf8_lossy(&machine.output.inner).into_owned()
+}
+
+// Trace destination the test can still read after handing it to a machine
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SharedBuffer {
+    fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.borrow()).into_owned()
+    }
+}
+
+// Error message of a run that must fail
+fn run_error(machine: &mut TestMachine) -> String {
+    machine.run().expect_err("program should have failed").to_string()
+}
+
+#[test]
+fn stack_growing_into_the_heap_collides() {
+    // The block's header is at 4 (just past the program) and its words at
+    // 5-1013, leaving the stack the ten words 1014-1023
+    let mut vm = machine(&[
+        0xB900_03F1, // alloc 1009
+        0xF000_0001, // loop: push 1
+        0x7FFF_FFFC, //   goto loop
+        0x0000_0000, // exit 0
+    ], &[]);
+    let error = run_error(&mut vm);
+    assert!(error.starts_with("heap/stack collision: SP 1013 would enter the heap, which ends at 1014"), "{}", error);
+}
+
+#[test]
+fn heap_growing_into_the_stack_collides() {
+    let mut vm = machine(&[
+        0xF000_0001, // push 1 (SP 1023)
+        0xF000_0002, // push 2 (SP 1022)
+        0xB900_0040, // loop: alloc 64
+        0x7FFF_FFFC, //   goto loop
+        0x0000_0000, // exit 0
+    ], &[]);
+    let error = run_error(&mut vm);
+    assert!(error.starts_with("heap/stack collision: the heap would end at"), "{}", error);
+    assert!(error.contains("but SP is"), "{}", error);
+}
+
+// Counts down from 30, printing the cycles left and the count from a subroutine
+const COUNTDOWN: [u32; 11] = [
+    0xF000_001E, // push 30
+    0x5000_0014, // loop: call show
+    0xF000_0001, //   push 1
+    0x2100_0000, //   sub
+    0x92FF_FFF4, //   ifnz loop
+    0x0000_0000, // exit 0
+    0x0300_0001, // show: cyclesleft
+    0xD000_0000, //   print
+    0x1000_0004, //   pop
+    0xD000_0004, //   print 4
+    0x6000_0000, //   return
+];
+
+// A countdown machine with the settings a checkpoint must carry across
+fn countdown() -> TestMachine {
+    let mut vm = machine(&COUNTDOWN, &[]);
+    vm.max_cycles = Some(1000);
+    vm.frame_check = FrameCheck::Strict;
+    vm.word_width = WordWidth::Bits16;
+    vm
+}
+
+#[test]
+fn resumed_checkpoint_continues_identically() {
+    let mut whole = countdown();
+    assert_eq!(whole.run().unwrap(), 0);
+
+    // Stop after 100 cycles, inside a call, and save the machine
+    let mut first = countdown();
+    for _ in 0..100 {
+        assert_eq!(first.step_instruction().unwrap(), None);
+    }
+    let bytes = first.snapshot().to_bytes();
+    let state = MachineState::from_bytes(&bytes).unwrap();
+    assert_eq!(state, first.snapshot());
+
+    let mut rest = Machine::from_state(state, Cursor::new(Vec::new()), Vec::new(), NullHost);
+    rest.max_cycles = Some(1000);
+    rest.frame_check = FrameCheck::Strict;
+    assert_eq!(rest.run().unwrap(), 0);
+
+    assert_eq!(output(&first) + &output(&rest), output(&whole));
+    assert_eq!(rest.snapshot(), whole.snapshot());
+}
+
+// Prints three random numbers
+const RANDOMS: [u32; 7] = [
+    0x0A00_0000, // rand
+    0xD000_0000, // print
+    0x0A00_0000, // rand
+    0xD000_0000, // print
+    0x0A00_0000, // rand
+    0xD000_0000, // print
+    0x0000_0000, // exit 0
+];
+
+// Output of RANDOMS with the generator seeded by `seed`
+fn randoms(seed: u64) -> String {
+    let mut vm = machine(&RANDOMS, &[]);
+    vm.seed_rng(seed);
+    vm.run().unwrap();
+    output(&vm)
+}
+
+#[test]
+fn same_seed_gives_the_same_numbers() {
+    assert_eq!(randoms(7), randoms(7));
+    assert_eq!(randoms(7).lines().count(), 3);
+}
+
+#[test]
+fn different_seeds_give_different_numbers() {
+    assert_ne!(randoms(7), randoms(8));
+}
+
+#[test]
+fn resumed_checkpoint_continues_the_random_sequence() {
+    let whole = randoms(7);
+
+    // Save after the first rand and print, then finish from the saved state
+    let mut first = machine(&RANDOMS, &[]);
+    first.seed_rng(7);
+    first.step_instruction().unwrap();
+    first.step_instruction().unwrap();
+    let state = MachineState::from_bytes(&first.snapshot().to_bytes()).unwrap();
+    let mut rest = Machine::from_state(state, Cursor::new(Vec::new()), Vec::new(), NullHost);
+    rest.run().unwrap();
+
+    assert_eq!(output(&first) + &output(&rest), whole);
+}
+
+#[test]
+fn resumed_checkpoint_keeps_trap_handlers() {
+    let code = [
+        0x0C00_0008, // settrap 0 handler
+        0xF000_0007, // push 7
+        0xF000_0000, // push 0
+        0x2300_0000, // div
+        0xD000_0000, // print
+        0x0000_0000, // exit 0
+        0x0000_0000,
+        0x0000_0000,
+        0x1000_0004, // handler: pop 4 (the trap number)
+        0xF000_0063, //   push 99
+        0x0100_0001, //   swap 0 1
+        0x6000_0000, //   return 0
+    ];
+
+    // Install the handler, then divide by zero in the resumed machine
+    let mut first = machine(&code, &[]);
+    first.step_instruction().unwrap();
+    let state = MachineState::from_bytes(&first.snapshot().to_bytes()).unwrap();
+    let mut rest = Machine::from_state(state, Cursor::new(Vec::new()), Vec::new(), NullHost);
+
+    assert_eq!(rest.run().unwrap(), 0);
+    assert_eq!(output(&rest), "99\n");
+}
+
+#[test]
+fn state_with_a_pool_outside_the_program_is_rejected() {
+    let mut state = machine(&[0x0000_0000], &[]).snapshot();
+    state.pool_base = 5000;
+    state.pool_len = 1;
+    let error = MachineState::from_bytes(&state.to_bytes()).unwrap_err();
+    assert_eq!(error.to_string(), "machine state has a constant pool outside the program");
+}
+
+#[test]
+fn resumed_checkpoint_keeps_the_constant_pool() {
+    // Version 3: a one-word pool holding 1234 follows the code
+    let file = [VERSIONED_MAGIC, 3, 1, 0xA900_0000, 0xD000_0000, 0x0000_0000, 1234];
+    let mut first = Machine::with_input_lines(&[], Vec::new());
+    first.load(&file).unwrap();
+    let state = MachineState::from_bytes(&first.snapshot().to_bytes()).unwrap();
+    let mut rest = Machine::from_state(state, Cursor::new(Vec::new()), Vec::new(), NullHost);
+
+    assert_eq!(rest.run().unwrap(), 0);
+    assert_eq!(output(&rest), "1234\n");
+}
+
+#[test]
+fn two_heap_blocks_store_load_and_free() {
+    let mut vm = machine(&[
+        0xB900_0002, // alloc 2 (a)
+        0xB900_0003, // alloc 3 (b)
+        0xF000_000B, // push 11
+        0xC000_0004, // dup 4
+        0xB500_0000, // storeabs 0 (b[0] = 11)
+        0xF000_0016, // push 22
+        0xC000_0008, // dup 8
+        0xB500_0000, // storeabs 0 (a[0] = 22)
+        0xC000_0000, // dup 0
+        0xB400_0000, // loadabs 0 (b[0])
+        0xD000_0000, // print
+        0x1000_0004, // pop 4
+        0xC000_0004, // dup 4
+        0xB400_0000, // loadabs 0 (a[0])
+        0xD000_0000, // print
+        0x1000_0004, // pop 4
+        0xBA00_0000, // dealloc (b)
+        0xBA00_0000, // dealloc (a)
+        0x0000_0000, // exit 0
+    ], &[]);
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(output(&vm), "11\n22\n");
+
+    // Both blocks were at the top of the heap, so all of it is given back
+    assert_eq!(vm.heap_top, vm.heap_base);
+}
+
+#[test]
+fn free_inside_a_block_is_rejected() {
+    // The block's header is at 10 and its words at 11-14; address 13 follows
+    // a word that looks like a live header
+    let mut vm = machine(&[
+        0xB900_0004, // alloc 4
+        0xF800_0001, // push 0xF8000001
+        0xC000_0004, // dup 4
+        0xF000_0001, // push 1
+        0x2000_0000, // add
+        0xB500_0000, // storeabs 0 (word 12)
+        0xF000_0002, // push 2
+        0x2000_0000, // add
+        0xBA00_0000, // dealloc 13
+        0x0000_0000, // exit 0
+    ], &[]);
+    assert_eq!(run_error(&mut vm), "(heap) free of 13, which is not an allocated block");
+    assert_eq!(vm.ram[12], 0xF800_0001);
+}
+
+#[test]
+fn state_with_a_heap_outside_ram_is_rejected() {
+    let mut state = machine(&[0x0000_0000], &[]).snapshot();
+    state.heap_base = 2000;
+    state.heap_top = 2000;
+    let error = MachineState::from_bytes(&state.to_bytes()).unwrap_err();
+    assert_eq!(error.to_string(), "machine state has a heap outside RAM");
+}
+
+#[test]
+fn state_with_sp_outside_the_stack_is_rejected() {
+    let mut state = machine(&[0x0000_0000], &[]).snapshot();
+    state.sp = -5;
+    let error = MachineState::from_bytes(&state.to_bytes()).unwrap_err();
+    assert_eq!(error.to_string(), "machine state has a stack pointer outside the stack");
+}
+
+// push 1, push 2, add, exit 0
+const SUM: [u32; 4] = [0xF000_0001, 0xF000_0002, 0x2000_0000, 0x0000_0000];
+
+// PC and SP before each instruction of SUM
+const SUM_TRACE: &str = "\
+{\"pc\":0,\"sp\":1024}
+{\"pc\":1,\"sp\":1023}
+{\"pc\":2,\"sp\":1022}
+{\"pc\":3,\"sp\":1023}
+";
+
+#[test]
+fn run_against_a_matching_trace_succeeds() {
+    let trace = parse_trace(SUM_TRACE).unwrap();
+    assert_eq!(machine(&SUM, &[]).run_against(&trace).unwrap(), 0);
+}
+
+#[test]
+fn run_against_a_corrupted_trace_reports_the_diverging_step() {
+    let trace = parse_trace(&SUM_TRACE.replace("\"pc\":2,\"sp\":1022", "\"pc\":2,\"sp\":1000")).unwrap();
+    let error = machine(&SUM, &[]).run_against(&trace).unwrap_err().to_string();
+    assert!(error.starts_with("(trace) divergence at step 2: expected pc=2 sp=1000, got pc=2 sp=1022\n"), "{}", error);
+}
+
+// Loads `file` and runs it to completion, returning the exit code and output
+fn run_file(file: &[u32]) -> (u8, String) {
+    let mut vm = Machine::with_input_lines(&[], Vec::new());
+    vm.load(file).unwrap();
+    (vm.run().unwrap(), output(&vm))
+}
+
+#[test]
+fn versioned_and_headerless_version_1_files_load_alike() {
+    // push 5, print, exit 3
+    let code = [0xF000_0005, 0xD000_0000, 0x0000_0003];
+    let expected = (3, String::from("5\n"));
+
+    assert_eq!(run_file(&[MAGIC, code[0], code[1], code[2]]), expected);
+    assert_eq!(run_file(&[VERSIONED_MAGIC, 1, code[0], code[1], code[2]]), expected);
+}
+
+#[test]
+fn version_2_files_load() {
+    assert_eq!(run_file(&[VERSIONED_MAGIC, 2, 0xF000_0005, 0xD000_0000, 0x0000_0003]), (3, String::from("5\n")));
+}
+
+#[test]
+fn unknown_program_versions_are_rejected() {
+    let mut vm = Machine::with_input_lines(&[], Vec::new());
+    assert_eq!(vm.load(&[VERSIONED_MAGIC, 9, 0x0000_0000]).unwrap_err().to_string(), "unsupported program version 9");
+}
+
+// inputn 3, then print the three numbers from the top down
+const INPUT_THREE: [u32; 5] = [0x0E00_0303, 0xD000_0000, 0xD000_0004, 0xD000_0008, 0x0000_0000];
+
+#[test]
+fn inputn_reads_numbers_from_one_line() {
+    let mut vm = machine(&INPUT_THREE, &["1 2 3"]);
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(output(&vm), "3\n2\n1\n");
+}
+
+#[test]
+fn inputn_reads_numbers_across_lines() {
+    let mut vm = machine(&INPUT_THREE, &["1", "2 3"]);
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(output(&vm), "3\n2\n1\n");
+}
+
+#[test]
+fn inputn_discards_the_rest_of_its_last_line() {
+    let mut vm = machine(&[0x0E00_0203, 0x0400_0000, 0xD000_0000, 0x0000_0000], &["1 2 3", "4"]); // inputn 2, input, print
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(output(&vm), "4
+");
+    assert_eq!(vm.stack(), vec![4, 2, 1]);
+}
+
+// The instruction a word that must be valid decodes to
+fn decode(inst: u32) -> Instruction {
+    Instruction::decode(inst).unwrap()
+}
+
+#[test]
+fn swap_decodes_two_raw_12_bit_fields() {
+    assert_eq!(decode(0x0112_3456), Instruction::Swap(0x123, 0x456));
+    assert_eq!(decode(0x01FF_F800), Instruction::Swap(0xFFF, 0x800));
+}
+
+#[test]
+fn binary_if_sign_extends_a_23_bit_offset() {
+    // Condition in bits 25-27, offset in bits 2-24
+    assert_eq!(decode(0x8000_0004), Instruction::BinaryIf(0, 1));
+    assert_eq!(decode(0x8BFF_FFFC), Instruction::BinaryIf(5, -1));
+    assert_eq!(decode(0x80FF_FFFC), Instruction::BinaryIf(0, 0x3F_FFFF));
+    assert_eq!(decode(0x8200_0000), Instruction::BinaryIf(1, 0));
+    assert_eq!(decode(0x8100_0000), Instruction::BinaryIf(0, -0x40_0000));
+}
+
+#[test]
+fn unary_if_sign_extends_a_24_bit_offset() {
+    assert_eq!(decode(0x9000_0008), Instruction::EqZero(8));
+    assert_eq!(decode(0x92FF_FFFC), Instruction::NeZero(-4));
+    assert_eq!(decode(0x947F_FFFC), Instruction::LtZero(0x7F_FFFC));
+    assert_eq!(decode(0x9680_0000), Instruction::GeZero(-0x80_0000));
+    assert_eq!(decode(0x9E00_0004), Instruction::IfNotEmpty(4));
+}
+
+#[test]
+fn push_sign_extends_a_28_bit_value() {
+    assert_eq!(decode(0xF000_0005), Instruction::Push(5));
+    assert_eq!(decode(0xF7FF_FFFF), Instruction::Push(0x07FF_FFFF));
+    assert_eq!(decode(0xF800_0000), Instruction::Push(0xF800_0000));
+    assert_eq!(decode(0xFFFF_FFFF), Instruction::Push(u32::MAX));
+}
+
+#[test]
+fn stprint_sign_extends_a_28_bit_offset_and_reads_the_counted_bit() {
+    assert_eq!(decode(0x4000_0008), Instruction::Stprint(8, false));
+    assert_eq!(decode(0x4000_0009), Instruction::Stprint(8, true));
+    assert_eq!(decode(0x4FFF_FFFC), Instruction::Stprint(-4, false));
+    assert_eq!(decode(0x4FFF_FFFD), Instruction::Stprint(-4, true));
+    assert_eq!(decode(0x4800_0000), Instruction::Stprint(-0x800_0000, false));
+}
+
+#[test]
+fn input_reads_the_lenient_bit_and_radix_bits() {
+    assert_eq!(decode(0x0400_0000), Instruction::Input(false, 0));
+    assert_eq!(decode(0x0400_0001), Instruction::Input(true, 0));
+    assert_eq!(decode(0x0400_0002), Instruction::Input(false, 10));
+    assert_eq!(decode(0x0400_0005), Instruction::Input(true, 16));
+    assert_eq!(decode(0x0400_0006), Instruction::Input(false, 2));
+}
+
+// Host whose syscall 1 pushes 42
+struct AnswerHost;
+
+impl HostEnv for AnswerHost {
+    fn syscall(&mut self, num: u32, stack: &mut [u32], sp: &mut i16) -> Result<(), VmError> {
+        if num != 1 {
+            return Err(format!("no syscall {}", num).into());
+        }
+        *sp -= 1;
+        stack[*sp as usize] = 42;
+        Ok(())
+    }
+}
+
+#[test]
+fn syscall_dispatches_to_the_host() {
+    let mut vm = Machine::new(Cursor::new(Vec::new()), Vec::new(), AnswerHost);
+    vm.load(&program(&[0x0600_0001, 0xD000_0000, 0x0000_0000])).unwrap(); // syscall 1, print, exit 0
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(vm.output.inner, b"42\n");
+}
+
+#[test]
+fn syscall_without_a_host_is_an_error() {
+    let mut vm = machine(&[0x0600_0001, 0x0000_0000], &[]);
+    assert_eq!(run_error(&mut vm), "(syscall) no host handler for syscall 1");
+}
+
+// Calls a subroutine that pushes a word and returns with `ret`
+fn frame_program(ret: u32) -> TestMachine {
+    let mut vm = machine(&[
+        0x5000_0008, // call sub
+        0x0000_0000, // exit 0
+        0xF000_0001, // sub: push 1
+        ret,
+    ], &[]);
+    vm.frame_check = FrameCheck::Strict;
+    vm
+}
+
+#[test]
+fn strict_frames_reject_an_unbalanced_return() {
+    let mut vm = frame_program(0x6000_0000); // return 0, leaving the pushed word
+    assert_eq!(run_error(&mut vm), "(return) unbalanced stack returning to 1: 1 word(s) more than at call");
+}
+
+#[test]
+fn strict_frames_accept_a_balanced_return() {
+    let mut vm = frame_program(0x6000_0004); // return 4, popping the pushed word
+    assert_eq!(vm.run().unwrap(), 0);
+}
+
+#[test]
+fn print_without_newline_keeps_numbers_on_one_line() {
+    let mut vm = machine(&[
+        0xF000_0001, // push 1
+        0x0700_0000, // printn
+        0xF000_0002, // push 2
+        0x0700_0000, // printn
+        0xF000_0003, // push 3
+        0x0700_0000, // printn
+        0x0000_0000, // exit 0
+    ], &[]);
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(output(&vm), "123");
+}
+
+// The word Input stores for a typed line
+fn input_word(line: &str) -> u32 {
+    let mut vm = machine(&[0x0400_0000, 0x0000_0000], &[line]); // input, exit 0
+    vm.run().unwrap();
+    vm.stack()[0]
+}
+
+#[test]
+fn input_takes_full_32_bit_hex_patterns() {
+    assert_eq!(input_word("0xFFFFFFFF"), 0xFFFF_FFFF);
+    assert_eq!(input_word("0b10000000000000000000000000000001"), 0x8000_0001);
+}
+
+#[test]
+fn input_negates_prefixed_numbers() {
+    assert_eq!(input_word("-0x1"), 0xFFFF_FFFF);
+    assert_eq!(input_word("-0b10"), 0xFFFF_FFFE);
+    assert_eq!(input_word("-7"), -7i32 as u32);
+}
+
+// Exit code and output of `code` run with the stack growing in `direction`
+fn run_growing(code: &[u32], direction: StackDirection) -> (u8, String) {
+    let mut vm = Machine::with_input_lines(&[], Vec::new());
+    vm.direction = direction;
+    vm.load(&program(code)).unwrap();
+    (vm.run().unwrap(), output(&vm))
+}
+
+#[test]
+fn programs_run_the_same_with_either_stack_direction() {
+    let sum = [
+        0xF000_0007, // push 7
+        0xF000_0005, // push 5
+        0x2100_0000, // sub
+        0xC000_0000, // dup 0
+        0xD000_0000, // print
+        0x0000_0002, // exit 2
+    ];
+    for code in [&sum[..], &COUNTDOWN[..]] {
+        assert_eq!(run_growing(code, StackDirection::Down), run_growing(code, StackDirection::Up));
+    }
+    assert_eq!(run_growing(&sum, StackDirection::Up), (2, String::from("2\n")));
+}
+
+#[test]
+fn describe_reports_the_loaded_program() {
+    let vm = machine(&[0xF000_0005, 0x0000_0000], &[]);
+    assert_eq!(vm.describe(), "\
+Loaded 2 instruction word(s)
+RAM size: 1024 words
+Initial SP: 1024
+Entry point: 0
+First instructions:
+  0000: f0000005  Push(5)
+  0001: 00000000  Exit(0)
+");
+}
+
+#[test]
+fn guard_stack_stops_pc_running_into_pushed_data() {
+    // Growing up, the stack starts just past the code, so with no exit
+    // the PC runs straight on into the pushed words
+    let mut vm = Machine::with_input_lines(&[], Vec::new());
+    vm.direction = StackDirection::Up;
+    vm.guard_stack = true;
+    vm.load(&program(&[0xF000_0005, 0xF000_0006])).unwrap(); // push 5, push 6
+    assert_eq!(run_error(&mut vm), "(fetch) PC 2 is executing live stack data (SP 3)");
+}
+
+#[test]
+fn dupn_copies_a_block_of_words() {
+    let mut vm = machine(&[
+        0xF000_0001, // push 1
+        0xF000_0002, // push 2
+        0xF000_0003, // push 3
+        0xF000_0009, // push 9
+        0xA000_4003, // dupn 4 3
+        0x0000_0000, // exit 0
+    ], &[]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [3, 2, 1, 9, 3, 2, 1]);
+}
+
+#[test]
+fn binary_if_on_a_short_stack_is_an_error() {
+    let message = "(binary if) stack underflow: comparison needs two words";
+    assert_eq!(run_error(&mut machine(&[0x8000_0004, 0x0000_0000], &[])), message); // ifeq
+    assert_eq!(run_error(&mut machine(&[0xF000_0001, 0x8000_0004, 0x0000_0000], &[])), message); // push 1, ifeq
+}
+
+#[test]
+fn unary_if_on_an_empty_stack_is_an_error() {
+    assert_eq!(run_error(&mut machine(&[0x9000_0004, 0x0000_0000], &[])), "(unary if) stack underflow: test needs one word");
+}
+
+#[test]
+fn jsonl_trace_has_one_object_per_instruction() {
+    let trace = SharedBuffer::default();
+    let mut vm = machine(&[0xF000_0005, 0x0000_0003], &[]); // push 5, exit 3
+    vm.trace = Some(TraceFormat::Jsonl);
+    vm.trace_output = Some(Box::new(trace.clone()));
+    assert_eq!(vm.run().unwrap(), 3);
+
+    let text = trace.text();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines, [
+        r#"{"pc":0,"sp":1024,"opcode":15,"mnemonic":"push"}"#,
+        r#"{"pc":1,"sp":1023,"opcode":0,"mnemonic":"exit","stack_top":5}"#,
+    ]);
+    assert_eq!(parse_trace(&text).unwrap(), [TraceStep { pc: 0, sp: 1024 }, TraceStep { pc: 1, sp: 1023 }]);
+}
+
+// Result of the binary instruction `op` on two numbers typed at Input prompts
+fn binary_op(op: u32, left: &str, right: &str) -> i32 {
+    let mut vm = machine(&[0x0400_0000, 0x0400_0000, op, 0x0000_0000], &[left, right]); // input, input, op, exit 0
+    vm.run().unwrap();
+    vm.stack()[0] as i32
+}
+
+#[test]
+fn saturating_arithmetic_clamps_at_both_bounds() {
+    let (max, min) = ("2147483647", "-2147483648");
+    assert_eq!(binary_op(0x2A00_0000, max, "1"), i32::MAX); // satadd
+    assert_eq!(binary_op(0x2A00_0000, min, "-1"), i32::MIN);
+    assert_eq!(binary_op(0x2C00_0000, min, "1"), i32::MIN); // satsub
+    assert_eq!(binary_op(0x2C00_0000, max, "-1"), i32::MAX);
+    assert_eq!(binary_op(0x2D00_0000, max, "2"), i32::MAX); // satmul
+    assert_eq!(binary_op(0x2D00_0000, min, "2"), i32::MIN);
+    assert_eq!(binary_op(0x2D00_0000, "-6", "7"), -42);
+}
+
+#[test]
+fn word_written_mid_run_changes_what_the_program_sees() {
+    let mut vm = machine(&[0xF000_0005, 0xD000_0000, 0x0000_0000], &[]); // push 5, print, exit 0
+    vm.step_instruction().unwrap();
+    assert_eq!(vm.read_word(1023).unwrap(), 5);
+
+    vm.write_word(1023, 99).unwrap();
+    vm.run().unwrap();
+    assert_eq!(output(&vm), "99\n");
+}
+
+#[test]
+fn words_outside_ram_cannot_be_read_or_written() {
+    let mut vm = machine(&[0x0000_0000], &[]);
+    assert!(vm.read_word(1024).is_err());
+    assert!(vm.write_word(1024, 1).is_err());
+}
+
+#[test]
+fn mul64_gives_the_full_product_as_a_word_pair() {
+    // 100000 * 100000 = 0x2_540B_E400
+    let mut vm = machine(&[
+        0xF001_86A0, // push 100000 (low)
+        0xF000_0000, // push 0 (high)
+        0xF001_86A0, // push 100000 (low)
+        0xF000_0000, // push 0 (high)
+        0xA100_0002, // mul64
+        0xD000_0000, // print (high)
+        0xD000_0004, // print 4 (low)
+        0x0000_0000, // exit 0
+    ], &[]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [2, 0x540B_E400]);
+    assert_eq!(output(&vm), "2\n1410065408\n");
+}
+
+#[test]
+fn clearstack_empties_the_stack() {
+    let mut vm = machine(&[
+        0xF000_0001, // push 1
+        0xF000_0002, // push 2
+        0xF000_0003, // push 3
+        0x0800_0000, // clearstack
+        0x0300_0005, // pushsp
+        0xD000_0000, // print
+        0x0000_0000, // exit 0
+    ], &[]);
+    vm.run().unwrap();
+    assert_eq!(output(&vm), "1024\n");
+    assert_eq!(vm.stack(), [1024]);
+}
+
+#[test]
+fn lenient_input_lets_a_program_prompt_again() {
+    let mut vm = machine(&[
+        0x0400_0001, // retry: input lenient (0 if the line is not a number)
+        0x0300_0000, //   inputfailed
+        0x9000_000C, //   ifeqz done
+        0x1000_0008, //   pop 8
+        0x7FFF_FFF0, //   goto retry
+        0x1000_0004, // done: pop 4
+        0xD000_0000, //   print
+        0x0000_0000, //   exit 0
+    ], &["abc", "17"]);
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(output(&vm), "17\n");
+    assert_eq!(vm.cycles, 11, "one failed and one good pass");
+}
+
+// cyclesleft three times, exit 0
+const CYCLES_LEFT: [u32; 4] = [0x0300_0001, 0x0300_0001, 0x0300_0001, 0x0000_0000];
+
+#[test]
+fn cycles_left_counts_down_to_the_limit() {
+    let mut vm = machine(&CYCLES_LEFT, &[]);
+    vm.max_cycles = Some(10);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [7, 8, 9]);
+}
+
+#[test]
+fn cycles_left_without_a_limit_is_the_largest_word() {
+    let mut vm = machine(&CYCLES_LEFT, &[]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [u32::MAX; 3]);
+}
+
+// Zero-pads decimal values to 8 digits
+struct ZeroPadded;
+
+impl PrintFormatter for ZeroPadded {
+    fn format(&self, value: u32, _radix: u32) -> String {
+        format!("{:08}", value as i32)
+    }
+}
+
+#[test]
+fn installed_formatter_renders_printed_values() {
+    let mut vm = machine(&[0xF000_0539, 0xD000_0000, 0x0000_0000], &[]); // push 1337, print, exit 0
+    vm.formatter = Box::new(ZeroPadded);
+    vm.run().unwrap();
+    assert_eq!(output(&vm), "00001337\n");
+}
+
+#[test]
+fn swap_past_the_stack_is_an_error() {
+    let mut vm = machine(&[0xF000_0001, 0x0100_0005, 0x0000_0000], &[]); // push 1, swap 0 5, exit 0
+    assert_eq!(run_error(&mut vm), "(swap) swap out of bounds");
+}
+
+#[test]
+fn crlf_input_lines_lose_the_carriage_return() {
+    assert_eq!(input_word("42\r"), 42);
+
+    // stinput 10 counted, stprint counted, exit 0
+    let mut vm = machine(&[0x0580_000A, 0x4000_0001, 0x0000_0000], &["hi\r"]);
+    vm.run().unwrap();
+    assert_eq!(output(&vm), "hi");
+    assert_eq!(vm.stack()[0], 2, "the string's length");
+}
+
+#[test]
+fn disassembly_labels_a_backward_loop() {
+    let listing = disassemble(&[
+        0xF000_0003, // push 3
+        0xF000_0001, // loop: push 1
+        0x2100_0000, //   sub
+        0x7FFF_FFF8, //   goto loop
+        0x0000_0000, // exit 0
+    ]);
+    assert_eq!(listing, "    0000: push 3
+L0:
+    0001: push 1
+    0002: sub
+    0003: goto -2                  # L0
+    0004: exit 0
+");
+}
+
+#[test]
+fn mulhigh_gives_the_upper_half_of_the_product() {
+    let (left, right) = (0x1234_5678u32, 0x9ABC_DEF0u32);
+    let signed = left as i32 as i64 * right as i32 as i64;
+    let unsigned = left as u64 * right as u64;
+    let (left, right) = ("0x12345678", "0x9ABCDEF0");
+
+    assert_eq!(binary_op(0x2200_0000, left, right), signed as i32); // mul keeps the low half
+    assert_eq!(binary_op(0x2E00_0000, left, right), (signed >> 32) as i32);
+    assert_eq!(binary_op(0x2F00_0000, left, right), (unsigned >> 32) as i32);
+    assert_ne!(signed >> 32, (unsigned >> 32) as i64, "the operands should tell signed and unsigned apart");
+}
+
+#[test]
+fn reset_keeps_one_program_from_seeing_the_last() {
+    let mut vm = machine(&[0xF000_0009, 0xF000_0008, 0x0000_0000], &[]); // push 9, push 8, exit 0
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [8, 9]);
+
+    // The second program finds an empty stack and no leftover words
+    vm.reset();
+    vm.load(&program(&[0x0300_0005, 0xD000_0000, 0x0000_0000])).unwrap(); // pushsp, print, exit 0
+    vm.run().unwrap();
+    assert_eq!(output(&vm), "1024\n");
+    assert_eq!(vm.stack(), [1024]);
+    assert_eq!(vm.ram()[1022], 0);
+}
+
+#[test]
+fn slurp_input_packs_every_line_into_one_string() {
+    let mut vm = machine(&[0x0900_0010, 0x4000_0000, 0x0000_0000], &["ab", "cd"]); // slurp 16, stprint, exit 0
+    vm.run().unwrap();
+
+    // Three bytes a word, first word on top and flagged as continued
+    assert_eq!(vm.stack(), [0x010A_6261, 0x000A_6463]);
+
+    // Stprint writes the terminating zero byte too, as it always has
+    assert_eq!(output(&vm), "ab\ncd\n\0");
+}
+
+#[test]
+fn slurp_of_empty_input_pushes_zero() {
+    let mut vm = machine(&[0x0900_0010, 0x0000_0000], &[]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [0]);
+}
+
+#[test]
+fn capacity_and_free_report_the_stack_room() {
+    let mut vm = machine(&[
+        0x0300_0003, // free (nothing pushed yet)
+        0x0300_0002, // capacity
+        0xF000_0001, // push 1
+        0xF000_0002, // push 2
+        0x0300_0003, // free (four words pushed)
+        0x0000_0000, // exit 0
+    ], &[]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [1020, 2, 1, 1024, 1024]);
+}
+
+#[test]
+fn magic_only_file_is_an_empty_program() {
+    let mut vm = Machine::with_input_lines(&[], Vec::new());
+    assert_eq!(vm.load(&[MAGIC]).unwrap_err().to_string(), "empty program");
+}
+
+#[test]
+fn function_keeps_two_locals_at_fixed_fp_offsets() {
+    // f(x) = 3x - (x + 1), with both terms in locals, returned in x's slot
+    let mut vm = machine(&[
+        0xF000_000A, // push 10
+        0x5000_000C, // call f
+        0xD000_0000, // print
+        0x0000_0000, // exit 0
+        0xB000_0000, // f: enterframe
+        0xF000_0000, //   push 0 (local at -4)
+        0xF000_0000, //   push 0 (local at -8)
+        0xB200_0008, //   loadfp 8 (x)
+        0xF000_0003, //   push 3
+        0x2200_0000, //   mul
+        0xB3FF_FFFC, //   storefp -4
+        0xB200_0008, //   loadfp 8
+        0xF000_0001, //   push 1
+        0x2000_0000, //   add
+        0xB3FF_FFF8, //   storefp -8
+        0xB2FF_FFFC, //   loadfp -4
+        0xB2FF_FFF8, //   loadfp -8
+        0x2100_0000, //   sub
+        0xB300_0008, //   storefp 8
+        0xB100_0000, //   leaveframe
+        0x6000_0000, //   return
+    ], &[]);
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(output(&vm), "19\n");
+    assert_eq!(vm.stack(), [19]);
+    assert_eq!(vm.fp, 1024, "the caller's FP is restored");
+}
+
+#[test]
+fn passing_assertion_continues() {
+    let mut vm = machine(&[0xF000_0001, 0x0B00_0000, 0x0000_0004], &[]); // push 1, assert, exit 4
+    assert_eq!(vm.run().unwrap(), 4);
+    assert!(vm.stack().is_empty());
+}
+
+#[test]
+fn failing_assertion_stops_the_program() {
+    let mut vm = machine(&[0xF000_0001, 0xF000_0000, 0x0B00_0000, 0x0000_0004], &[]); // push 1, push 0, assert, exit 4
+    assert_eq!(run_error(&mut vm), "assertion failed at pc 2");
+}
+
+// Output that only becomes visible (to the test) when flushed
+#[derive(Default)]
+struct FlushedOutput {
+    pending: Vec<u8>,
+    visible: SharedBuffer,
+}
+
+impl Write for FlushedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.pending.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.visible.write_all(&self.pending)?;
+        self.pending.clear();
+        Ok(())
+    }
+}
+
+// What is visible after each of: printn 1, print 1 (with a newline), exit
+fn visible_output(policy: FlushPolicy) -> [String; 3] {
+    let output = FlushedOutput::default();
+    let visible = output.visible.clone();
+    let mut vm = Machine::with_input_lines(&[], output);
+    vm.flush = policy;
+    vm.load(&program(&[0xF000_0001, 0x0700_0000, 0xD000_0000, 0x0000_0000])).unwrap();
+
+    vm.step_instruction().unwrap();
+    vm.step_instruction().unwrap();
+    let after_printn = visible.text();
+    vm.step_instruction().unwrap();
+    let after_print = visible.text();
+    vm.run().unwrap();
+    [after_printn, after_print, visible.text()]
+}
+
+#[test]
+fn flush_always_shows_each_print_at_once() {
+    assert_eq!(visible_output(FlushPolicy::Always), ["1", "11\n", "11\n"]);
+}
+
+#[test]
+fn flush_line_waits_for_a_newline() {
+    assert_eq!(visible_output(FlushPolicy::Line), ["", "11\n", "11\n"]);
+}
+
+#[test]
+fn flush_onexit_holds_everything_until_the_end() {
+    assert_eq!(visible_output(FlushPolicy::OnExit), ["", "", "11\n"]);
+}
+
+// Stprint output of NumToStr applied to a pushed value
+fn num_to_str(push: u32) -> String {
+    let mut vm = machine(&[push, 0xA200_0000, 0x4000_0000, 0x0000_0000], &[]); // push, numtostr, stprint, exit 0
+    vm.run().unwrap();
+    output(&vm)
+}
+
+#[test]
+fn numtostr_renders_signed_decimal() {
+    // Stprint writes the packed string's terminating zero byte too
+    assert_eq!(num_to_str(0xFFFF_FF85), "-123\0");
+    assert_eq!(num_to_str(0xF000_0000), "0\0");
+    assert_eq!(num_to_str(0xF000_1000), "4096\0");
+}
+
+#[test]
+fn strtonum_parses_a_packed_string() {
+    let mut vm = machine(&[
+        0xF001_0146, // push "F" (last word)
+        0xF131_7830, // push "0x1" (continued)
+        0xA300_0000, // strtonum
+        0x0000_0000, // exit 0
+    ], &[]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [1, 31]);
+}
+
+#[test]
+fn strtonum_flags_a_string_that_is_not_a_number() {
+    let mut vm = machine(&[0x0500_0010, 0xA300_0000, 0x0000_0000], &["zz"]); // stinput 16, strtonum, exit 0
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [0, 0]);
+}
+
+#[test]
+fn return_past_the_bottom_of_the_stack_is_an_error() {
+    let mut vm = machine(&[
+        0x5000_0008, // call sub
+        0x0000_0000, // exit 0
+        0x6000_0040, // sub: return 64, far more than the one word on the stack
+    ], &[]);
+    assert_eq!(run_error(&mut vm), "(return) return pop exceeds stack");
+}
+
+#[test]
+fn return_on_an_empty_stack_is_an_error() {
+    let mut vm = machine(&[0x6000_0000], &[]); // return 0 with no return address
+    assert_eq!(run_error(&mut vm), "(return) return pop exceeds stack");
+}
+
+#[test]
+fn duptop_pushes_copies_of_the_top_word() {
+    let mut vm = machine(&[0xF000_0005, 0xA400_0003, 0x0000_0000], &[]); // push 5, duptop 3, exit 0
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [5, 5, 5, 5]);
+}
+
+#[test]
+fn duptop_on_an_empty_stack_is_an_error() {
+    let mut vm = machine(&[0xA400_0001], &[]);
+    assert_eq!(run_error(&mut vm), "(duptop) stack underflow: nothing to copy");
+}
+
+#[test]
+fn sixteen_bit_arithmetic_wraps_at_sixteen_bits() {
+    let mut vm = machine(&[
+        0xF000_7FFF, // push 32767
+        0xF000_0001, // push 1
+        0x2000_0000, // add
+        0xD000_0000, // print
+        0xF000_012C, // push 300
+        0xF000_012C, // push 300
+        0x2200_0000, // mul
+        0xD000_0000, // print
+        0x0000_0000, // exit 0
+    ], &[]);
+    vm.word_width = WordWidth::Bits16;
+    vm.run().unwrap();
+    assert_eq!(output(&vm), "-32768\n24464\n");
+}
+
+#[test]
+fn thirty_two_bit_arithmetic_does_not_wrap_at_sixteen_bits() {
+    let mut vm = machine(&[0xF000_7FFF, 0xF000_0001, 0x2000_0000, 0xD000_0000, 0x0000_0000], &[]);
+    vm.run().unwrap();
+    assert_eq!(output(&vm), "32768\n");
+}
+
+// Exit 2 if bit 5 of `word` is set, else 1, showing the stack left behind
+fn bit_five(word: u32) -> (u8, Vec<u32>) {
+    let mut vm = machine(&[
+        0xF000_0000 | word, // push word
+        0xA528_0002,        // bittest 5 +2
+        0x0000_0001,        // exit 1
+        0x0000_0002,        // exit 2
+    ], &[]);
+    let code = vm.run().unwrap();
+    (code, vm.stack().to_vec())
+}
+
+#[test]
+fn bittest_branches_on_a_set_bit() {
+    assert_eq!(bit_five(0x20), (2, vec![0x20]));
+}
+
+#[test]
+fn bittest_falls_through_on_a_clear_bit() {
+    assert_eq!(bit_five(0x1F), (1, vec![0x1F]));
+}
+
+// The stack left by `rotation` on 1 2 3 (3 on top), listed from the top
+fn rotate(rotation: u32) -> Vec<u32> {
+    let mut vm = machine(&[0xF000_0001, 0xF000_0002, 0xF000_0003, rotation, 0x0000_0000], &[]);
+    vm.run().unwrap();
+    vm.stack().to_vec()
+}
+
+#[test]
+fn rotl3_moves_the_third_word_to_the_top() {
+    assert_eq!(rotate(0xA600_0000), [1, 3, 2]); // 2 3 1
+}
+
+#[test]
+fn rotr3_moves_the_top_word_to_third() {
+    assert_eq!(rotate(0xA600_0001), [2, 1, 3]); // 3 1 2
+}
+
+#[test]
+fn rotation_of_two_words_is_an_error() {
+    let mut vm = machine(&[0xF000_0001, 0xF000_0002, 0xA600_0000], &[]);
+    assert!(run_error(&mut vm).contains("stack underflow"));
+}
+
+#[test]
+fn strict_branches_reject_a_target_past_the_program() {
+    let code = [0x7000_07D0, 0x0000_0001]; // goto +500, into empty RAM; exit 1
+    let mut vm = machine(&code, &[]);
+    vm.strict_branches = true;
+    assert_eq!(run_error(&mut vm), "(branch) target 500 from PC 0 is outside the program");
+
+    // Without the check the zeroed word there runs as exit 0
+    let mut vm = machine(&code, &[]);
+    assert_eq!(vm.run().unwrap(), 0);
+}
+
+#[test]
+fn branch_past_the_end_of_ram_is_an_error_rather_than_wrapping() {
+    // goto +40000 would wrap an i16 round to -25536
+    let mut vm = machine(&[0x7002_7100, 0x0000_0001], &[]);
+    assert_eq!(run_error(&mut vm), "(branch) target 40000 from PC 0 is outside RAM");
+
+    let mut vm = machine(&[0xF000_0000, 0x90FF_FFF8], &[]); // push 0; ifez -2
+    assert_eq!(run_error(&mut vm), "(branch) target -1 from PC 1 is outside RAM");
+}
+
+#[test]
+fn stack_view_lists_the_stack_from_the_top() {
+    let mut vm = machine(&[0xF000_0007, 0xFFFF_FFFE, 0xF000_00FF, 0x0000_0000], &[]); // push 7, -2, 255; exit 0
+    vm.run().unwrap();
+
+    let view = vm.stack_view();
+    let addresses: Vec<usize> = view.iter().map(|entry| entry.address).collect();
+    let signed: Vec<i32> = view.iter().map(|entry| entry.as_signed).collect();
+    let hex: Vec<&str> = view.iter().map(|entry| entry.as_hex.as_str()).collect();
+    assert_eq!(addresses, [1021, 1022, 1023]);
+    assert_eq!(signed, [255, -2, 7]);
+    assert_eq!(hex, ["0x000000ff", "0xfffffffe", "0x00000007"]);
+    assert_eq!(view[1].value, 0xFFFF_FFFE);
+}
+
+#[test]
+fn hex_only_input_rejects_a_decimal_number() {
+    let mut vm = machine(&[0x0400_0004, 0x0000_0000], &["31"]); // input (hex), exit 0
+    assert_eq!(run_error(&mut vm), "(input) expected a hex (0x) number");
+
+    let mut vm = machine(&[0x0400_0004, 0x0000_0000], &["0x1f"]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [31]);
+}
+
+#[test]
+fn lenient_hex_only_input_pushes_zero_and_sets_the_flag() {
+    let mut vm = machine(&[0x0400_0005, 0x0300_0000, 0x0000_0000], &["31"]); // input (lenient hex), inputfailed, exit 0
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [1, 0]);
+}
+
+#[test]
+fn decimal_only_input_rejects_a_binary_number() {
+    let mut vm = machine(&[0x0400_0002], &["0b101"]);
+    assert_eq!(run_error(&mut vm), "(input) expected a decimal number");
+}
+
+#[test]
+fn output_limit_stops_a_printing_loop() {
+    let mut vm = machine(&[
+        0xF000_0001, // push 1
+        0xD000_0000, // loop: print
+        0x7FFF_FFFC, // goto loop
+    ], &[]);
+    vm.max_output = Some(10);
+    assert_eq!(run_error(&mut vm), "output limit exceeded");
+
+    // The print that crossed the limit is the last one written
+    assert_eq!(output(&vm), "1\n".repeat(6));
+}
+
+// The word compare pushes for `left cond right`
+fn compare(cond: u32, left: i32, right: i32) -> u32 {
+    let push = |v: i32| 0xF000_0000 | (v as u32 & 0x0FFF_FFFF);
+    let mut vm = machine(&[push(left), push(right), 0xA700_0000 | cond, 0x0000_0000], &[]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack().len(), 1, "compare replaces both words");
+    vm.stack()[0]
+}
+
+#[test]
+fn compare_pushes_each_condition_as_a_word() {
+    // eq, ne, lt, gt, le, ge; bit 4 asks for a signed comparison
+    let signed: Vec<u32> = (0..6).map(|cond| compare(0x10 | cond, -1, 2)).collect();
+    assert_eq!(signed, [0, 1, 1, 0, 1, 0]);
+
+    let unsigned: Vec<u32> = (0..6).map(|cond| compare(cond, -1, 2)).collect();
+    assert_eq!(unsigned, [0, 1, 0, 1, 0, 1]);
+
+    let equal: Vec<u32> = (0..6).map(|cond| compare(0x10 | cond, 5, 5)).collect();
+    assert_eq!(equal, [1, 0, 0, 0, 1, 1]);
+}
+
+// Copy 1 2 3 4 from word 100 to `dest` with memcopy, returning `words` of the result
+fn mem_copy(dest: u32, words: std::ops::Range<usize>) -> Vec<u32> {
+    let mut vm = machine(&[
+        0xF000_0004,        // push 4 (length)
+        0xF000_0064,        // push 100 (source)
+        0xF000_0000 | dest, // push dest
+        0xB600_0000,        // memcopy
+        0x0000_0000,        // exit 0
+    ], &[]);
+    for (i, value) in [1, 2, 3, 4].into_iter().enumerate() {
+        vm.write_word(100 + i, value).unwrap();
+    }
+    vm.run().unwrap();
+    words.map(|addr| vm.read_word(addr).unwrap()).collect()
+}
+
+#[test]
+fn memcopy_copies_a_separate_block() {
+    assert_eq!(mem_copy(200, 199..205), [0, 1, 2, 3, 4, 0]);
+    assert_eq!(mem_copy(200, 100..104), [1, 2, 3, 4], "the source is unchanged");
+}
+
+#[test]
+fn memcopy_handles_overlapping_blocks_in_both_directions() {
+    assert_eq!(mem_copy(102, 100..106), [1, 2, 1, 2, 3, 4]);
+    assert_eq!(mem_copy(99, 99..104), [1, 2, 3, 4, 4]);
+}
+
+#[test]
+fn echoed_input_is_interleaved_with_program_output() {
+    let mut vm = machine(&[
+        0x0400_0000, // input
+        0xF000_0001, // push 1
+        0x2000_0000, // add
+        0xD000_0000, // print
+        0x0400_0000, // input
+        0xD000_0000, // print
+        0x0000_0000, // exit 0
+    ], &["4", "0x10"]);
+    vm.echo_input = true;
+    vm.run().unwrap();
+    assert_eq!(output(&vm), "4\n5\n0x10\n16\n");
+}
+
+#[test]
+fn input_is_not_echoed_by_default() {
+    let mut vm = machine(&[0x0400_0000, 0xD000_0000, 0x0000_0000], &["4"]);
+    vm.run().unwrap();
+    assert_eq!(output(&vm), "4\n");
+}
+
+#[test]
+fn stats_record_the_deepest_nesting_of_calls() {
+    let mut vm = machine(&[
+        0x5000_000C, // call a
+        0x5000_0018, // call c
+        0x0000_0000, // exit 0
+        0x5000_0008, // a: call b
+        0x6000_0000, //   return
+        0x5000_0004, // b: call c
+        0x6000_0000, //   return
+        0x6000_0000, // c: return
+    ], &[]);
+    vm.run().unwrap();
+
+    let stats = vm.stats();
+    assert_eq!(stats.peak_call_depth, 3);
+    assert_eq!((stats.calls, stats.returns), (4, 4));
+}
+
+// Exit code after `skip` pops `word`: 1 if the next instruction ran, 2 if it was skipped
+fn skip_on(skip: u32, word: u32) -> u8 {
+    let mut vm = machine(&[
+        0xF000_0000 | word, // push word
+        skip,
+        0x0000_0001,        // exit 1
+        0x0000_0002,        // exit 2
+    ], &[]);
+    let code = vm.run().unwrap();
+    assert!(vm.stack().is_empty(), "the skip pops its word");
+    code
+}
+
+#[test]
+fn skip_if_zero_skips_only_on_zero() {
+    assert_eq!(skip_on(0xA800_0000, 0), 2);
+    assert_eq!(skip_on(0xA800_0000, 7), 1);
+}
+
+#[test]
+fn skip_if_nonzero_skips_only_on_nonzero() {
+    assert_eq!(skip_on(0xA800_0001, 7), 2);
+    assert_eq!(skip_on(0xA800_0001, 0), 1);
+}
+
+#[test]
+fn hook_sees_every_instruction_before_it_runs() {
+    let mut vm = machine(&[0xF000_0002, 0xF000_0003, 0x2000_0000, 0x0000_0000], &[]); // push 2, push 3, add, exit 0
+    let seen = Rc::new(RefCell::new(Vec::new()));
+    let log = Rc::clone(&seen);
+    vm.set_hook(Box::new(move |machine, inst| log.borrow_mut().push((machine.pc(), format!("{:?}", inst)))));
+    vm.run().unwrap();
+
+    let seen = seen.borrow();
+    assert_eq!(seen.len(), 4);
+    assert_eq!(seen.iter().map(|(pc, _)| *pc).collect::<Vec<_>>(), [0, 1, 2, 3]);
+    assert!(seen[2].1.starts_with("Add"), "{:?}", seen[2]);
+}
+
+#[test]
+fn pushsp_pushes_the_stack_pointer_from_before_the_push() {
+    let mut vm = machine(&[
+        0x0300_0005, // pushsp
+        0xF000_0009, // push 9
+        0x0300_0005, // pushsp
+        0x0000_0000, // exit 0
+    ], &[]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [1022, 9, 1024]);
+}
+
+// Exit 2 if `unary_if` (offset +2) branches on `word`, else 1
+fn branches_on(unary_if: u32, word: u32) -> u8 {
+    let mut vm = machine(&[0xF000_0000 | word, unary_if, 0x0000_0001, 0x0000_0002], &[]);
+    vm.run().unwrap()
+}
+
+#[test]
+fn ifodd_branches_only_on_odd_words() {
+    assert_eq!(branches_on(0x9800_0008, 7), 2);
+    assert_eq!(branches_on(0x9800_0008, 8), 1);
+}
+
+#[test]
+fn ifeven_branches_only_on_even_words() {
+    assert_eq!(branches_on(0x9A00_0008, 8), 2);
+    assert_eq!(branches_on(0x9A00_0008, 0), 2);
+    assert_eq!(branches_on(0x9A00_0008, 7), 1);
+}
+
+#[test]
+fn raw_disassembly_shows_the_word_beside_the_mnemonic() {
+    assert_eq!(disassemble_raw(&[0xF000_0007]), "    0000: f0000007  push 7\n");
+    assert_eq!(disassemble(&[0xF000_0007]), "    0000: push 7\n");
+}
+
+#[test]
+fn flush_instruction_shows_output_before_exit_under_onexit() {
+    let output = FlushedOutput::default();
+    let visible = output.visible.clone();
+    let mut vm = Machine::with_input_lines(&[], output);
+    vm.flush = FlushPolicy::OnExit;
+    vm.load(&program(&[0xF000_0001, 0xD000_0000, 0x0D00_0000, 0x0000_0000])).unwrap(); // push 1, print, flush, exit 0
+
+    vm.step_instruction().unwrap();
+    vm.step_instruction().unwrap();
+    assert_eq!(visible.text(), "", "onexit holds the print");
+    vm.step_instruction().unwrap();
+    assert_eq!(visible.text(), "1\n", "flush shows it before exit");
+    assert_eq!(vm.run().unwrap(), 0);
+}
+
+#[test]
+fn program_loads_from_raw_bytes() {
+    let bytes: Vec<u8> = program(&[0xF000_0007, 0xD000_0000, 0x0000_0000]).iter().flat_map(|word| word.to_le_bytes()).collect();
+    let mut vm = Machine::with_input_lines(&[], Vec::new());
+    vm.load_bytes(&bytes).unwrap();
+    vm.run().unwrap();
+    assert_eq!(output(&vm), "7\n");
+}
+
+#[test]
+fn raw_bytes_must_be_whole_words_with_the_magic_first() {
+    let mut vm = Machine::with_input_lines(&[], Vec::new());
+    let error = vm.load_bytes(&[0xDE, 0xAD, 0xBE, 0xEF, 0x00, 0x00]).unwrap_err();
+    assert_eq!(error.to_string(), "6 byte(s) is not a whole number of 4-byte words");
+    assert!(vm.load_bytes(&[0x00, 0x00, 0x00, 0x00]).is_err(), "no magic word");
+    assert!(vm.load_bytes(&[]).is_err(), "empty file");
+}
+
+// Bytes 0-3 of word 100 (0x80FF7F01) read with `loadbyte`, based at byte 400
+fn load_bytes_of_word(loadbyte: u32) -> Vec<i32> {
+    (0..4)
+        .map(|index| {
+            let mut vm = machine(&[0xF000_0000 | index, loadbyte | 400, 0x0000_0000], &[]); // push index, loadbyte 400, exit 0
+            vm.write_word(100, 0x80FF_7F01).unwrap();
+            vm.run().unwrap();
+            vm.stack()[0] as i32
+        })
+        .collect()
+}
+
+#[test]
+fn loadbyte_reads_little_endian_bytes_sign_extended() {
+    assert_eq!(load_bytes_of_word(0xB700_0000), [1, 127, -1, -128]);
+}
+
+#[test]
+fn loadbyteu_reads_little_endian_bytes_zero_extended() {
+    assert_eq!(load_bytes_of_word(0xB800_0000), [1, 127, 255, 128]);
+}
+
+#[test]
+fn loadbyte_past_the_last_byte_of_ram_is_an_error() {
+    let mut vm = machine(&[0xF000_0001, 0xB800_0FFF], &[]); // push 1, loadbyteu 4095
+    assert_eq!(run_error(&mut vm), "(memory) byte address 4095+1 out of bounds");
+}
+
+// Exit 2 if `unary_if` (offset +2) branches after `setup`, else 1
+fn branches_after(setup: &[u32], unary_if: u32) -> u8 {
+    let code: Vec<u32> = setup.iter().copied().chain([unary_if, 0x0000_0001, 0x0000_0002]).collect();
+    machine(&code, &[]).run().unwrap()
+}
+
+#[test]
+fn ifempty_branches_only_on_an_empty_stack() {
+    assert_eq!(branches_after(&[], 0x9C00_0008), 2);
+    assert_eq!(branches_after(&[0xF000_0000], 0x9C00_0008), 1);
+}
+
+#[test]
+fn ifnotempty_branches_only_on_a_nonempty_stack() {
+    assert_eq!(branches_after(&[0xF000_0000], 0x9E00_0008), 2);
+    assert_eq!(branches_after(&[], 0x9E00_0008), 1);
+}
+
+#[test]
+fn run_until_stops_at_a_mid_program_address() {
+    let mut vm = machine(&[
+        0xF000_0007, // push 7
+        0xF000_0005, // push 5
+        0x2000_0000, // add
+        0xD000_0000, // print
+        0x0000_0000, // exit 0
+    ], &[]);
+    assert_eq!(vm.run_until(2).unwrap(), None);
+    assert_eq!((vm.pc(), vm.stack()), (2, vec![5, 7]));
+
+    // Already at the target, it does not move; past it the program runs to exit
+    assert_eq!(vm.run_until(2).unwrap(), None);
+    assert_eq!(vm.run_until(1).unwrap(), Some(0));
+    assert_eq!(output(&vm), "12\n");
+}
+
+// push 9, push 0, storeabs 0, exit 0: overwrites the first instruction
+const NULL_STORE: [u32; 4] = [0xF000_0009, 0xF000_0000, 0xB500_0000, 0x0000_0000];
+
+#[test]
+fn null_write_trap_stops_a_store_to_address_0() {
+    let mut vm = machine(&NULL_STORE, &[]);
+    vm.trap_null_write = true;
+    assert_eq!(run_error(&mut vm), "(memory) write to address 0 at PC 2");
+    assert_eq!(vm.read_word(0).unwrap(), 0xF000_0009, "the program is untouched");
+}
+
+#[test]
+fn null_write_trap_stops_a_memcopy_to_address_0() {
+    // push 1 (length), push 3 (source), push 0 (destination), memcopy
+    let mut vm = machine(&[0xF000_0001, 0xF000_0003, 0xF000_0000, 0xB600_0000], &[]);
+    vm.trap_null_write = true;
+    assert_eq!(run_error(&mut vm), "(memory) write to address 0 at PC 3");
+}
+
+#[test]
+fn store_to_address_0_is_allowed_by_default() {
+    let mut vm = machine(&NULL_STORE, &[]);
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(vm.read_word(0).unwrap(), 9);
+}
+
+#[test]
+fn stprint_reaches_a_string_above_the_top_of_the_stack() {
+    let mut vm = machine(&[
+        0x0500_0010, // stinput 16
+        0x1000_0004, // pop, leaving the string's word just past SP
+        0x4FFF_FFFC, // stprint -4
+        0x0000_0000, // exit 0
+    ], &["hi"]);
+    vm.run().unwrap();
+
+    // Stprint of a packed string also writes its terminating zero byte
+    assert_eq!(output(&vm), "hi\0");
+}
+
+// push 1, print, waitkey, input, print, exit 0
+const WAIT_KEY: [u32; 6] = [0xF000_0001, 0xD000_0000, 0x0E00_0000, 0x0400_0000, 0xD000_0000, 0x0000_0000];
+
+#[test]
+fn waitkey_consumes_one_key_and_continues() {
+    let mut vm = machine(&WAIT_KEY, &["k5"]);
+    vm.interactive = true;
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(output(&vm), "1\n5\n");
+}
+
+#[test]
+fn waitkey_at_the_end_of_input_continues() {
+    let mut vm = machine(&[0x0E00_0000, 0x0000_0003], &[]); // waitkey, exit 3
+    vm.interactive = true;
+    assert_eq!(vm.run().unwrap(), 3);
+}
+
+#[test]
+fn waitkey_leaves_piped_input_for_the_reads() {
+    let mut vm = machine(&WAIT_KEY, &["5"]);
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(output(&vm), "1\n5\n");
+}
+
+#[test]
+fn block_after_an_unconditional_goto_is_dead() {
+    let program = [
+        0x7000_000C, // goto end
+        0xF000_0001, // push 1 (never runs)
+        0xD000_0000, // print (never runs)
+        0x0000_0000, // end: exit 0
+    ];
+    assert_eq!(find_dead_code(&program, 0), [1, 2]);
+}
+
+#[test]
+fn program_taking_its_own_pc_has_no_reported_dead_code() {
+    assert_eq!(find_dead_code(&[0x0300_0004, 0x7000_0008, 0xF000_0001, 0x0000_0000], 0), []); // pushpc, goto +2, ...
+}
+
+#[test]
+fn dupop_mul_squares_the_top_word() {
+    let mut vm = machine(&[0xF000_0007, 0xAA00_0002, 0x0000_0000], &[]); // push 7, dupop mul, exit 0
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [49]);
+}
+
+#[test]
+fn dupop_add_doubles_and_sub_zeroes() {
+    let mut vm = machine(&[0xF000_0007, 0xAA00_0000, 0xF000_0003, 0xAA00_0001, 0x0000_0000], &[]); // push 7, dupop add, push 3, dupop sub
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [0, 14]);
+}
+
+#[test]
+fn dupop_on_an_empty_stack_is_an_error() {
+    let mut vm = machine(&[0xAA00_0002], &[]);
+    assert_eq!(run_error(&mut vm), "(dupop) stack underflow: nothing to copy");
+}
+
+#[test]
+fn lowered_initial_sp_leaves_the_region_above_it_alone() {
+    let mut vm = Machine::with_input_lines(&[], Vec::new());
+    vm.initial_sp = Some(1000);
+    vm.load(&program(&[0xF000_0001, 0xF000_0002, 0xF000_0003, 0x0000_0000])).unwrap(); // push 1, 2, 3; exit 0
+    for addr in 1000..1024 {
+        vm.write_word(addr, 0xAAAA).unwrap();
+    }
+    vm.run().unwrap();
+
+    assert_eq!(vm.stack(), [3, 2, 1]);
+    assert_eq!(vm.stack_view().last().unwrap().address, 999);
+    assert!((1000..1024).all(|addr| vm.read_word(addr).unwrap() == 0xAAAA));
+}
+
+#[test]
+fn initial_sp_inside_the_program_is_rejected() {
+    let mut vm = Machine::with_input_lines(&[], Vec::new());
+    vm.initial_sp = Some(1);
+    let error = vm.load(&program(&[0xF000_0001, 0x0000_0000])).unwrap_err();
+    assert_eq!(error.to_string(), "Initial SP 1 is outside the free RAM (2-1024)");
+}
+
+#[test]
+fn recent_instructions_end_at_the_one_that_failed() {
+    // push 1 .. push 9, push 0, div: only the last eight are kept
+    let mut code: Vec<u32> = (1..=9).map(|n| 0xF000_0000 | n).collect();
+    code.extend([0xF000_0000, 0x2300_0000]);
+    let mut vm = machine(&code, &[]);
+    assert!(vm.run().is_err());
+
+    let recent = vm.recent_instructions();
+    let lines: Vec<&str> = recent.lines().collect();
+    assert_eq!(lines.len(), 8);
+    assert_eq!(lines[0], "  0003: f0000004  push 4");
+    assert_eq!(lines[7], "  000a: 23000000  div");
+}
+
+// The value and flag inputorflag pushes for `line`
+fn input_or_flag(line: &str) -> Vec<u32> {
+    let mut vm = machine(&[0x0E00_0001, 0x0000_0000], &[line]); // inputorflag, exit 0
+    vm.run().unwrap();
+    vm.stack()
+}
+
+#[test]
+fn inputorflag_pushes_a_valid_number_and_a_set_flag() {
+    assert_eq!(input_or_flag("-12"), [1, (-12i32) as u32]);
+    assert_eq!(input_or_flag(" 0x1f "), [1, 31]);
+}
+
+#[test]
+fn inputorflag_pushes_zero_and_a_clear_flag_for_bad_input() {
+    assert_eq!(input_or_flag("twelve"), [0, 0]);
+    assert_eq!(input_or_flag(""), [0, 0]);
+}
+
+#[test]
+fn input_lines_feed_each_read_in_turn() {
+    let mut vm = Machine::with_input_lines(&["7", "abc", "0x10"], Vec::new());
+    vm.load(&program(&[
+        0x0400_0000, // input
+        0x0580_0010, // stinput 16 (counted)
+        0x4000_0001, // stprint 0 (counted)
+        0x0400_0000, // input
+        0xD000_0000, // print
+        0xD000_000C, // print 12
+        0x0000_0000, // exit 0
+    ])).unwrap();
+    vm.run().unwrap();
+    assert_eq!(output(&vm), "abc16\n7\n");
+}
+
+#[test]
+fn verify_reports_every_bad_branch() {
+    let error = verify_branches(&program(&[
+        0x7000_0040, // goto +16, past the code
+        0x5FFF_FFF8, // call -2, before the code
+        0x9000_0006, // ifeqz with an offset of 6 bytes
+        0x0000_0000, // exit 0
+    ])).unwrap_err().to_string();
+
+    let lines: Vec<&str> = error.lines().collect();
+    assert_eq!(lines[0], "(verify) 3 bad branch(es):");
+    assert!(lines[1].starts_with("    0000: ") && lines[1].ends_with("target 16 is outside the code (0-3)"), "{}", error);
+    assert!(lines[2].starts_with("    0001: ") && lines[2].ends_with("target -1 is outside the code (0-3)"), "{}", error);
+    assert!(lines[3].starts_with("    0002: ") && lines[3].ends_with("offset is not a whole number of words"), "{}", error);
+}
+
+#[test]
+fn verify_accepts_branches_inside_the_code() {
+    assert!(verify_branches(&program(&[0x7000_0008, 0x0000_0001, 0x7FFF_FFFC])).is_ok()); // goto +2, exit 1, goto -1
+}
+
+#[test]
+fn dumpline_prints_the_stack_on_one_line_top_first() {
+    let mut vm = machine(&[
+        0xF000_0001, // push 1
+        0xFFFF_FFFE, // push -2
+        0xF000_00FF, // push 255
+        0x0E00_0002, // dumpline
+        0x1000_000C, // pop 12
+        0x0E00_0002, // dumpline
+        0x0000_0000, // exit 0
+    ], &[]);
+    vm.run().unwrap();
+    assert_eq!(output(&vm), "000000ff fffffffe 00000001\n\n");
+}
+
+#[test]
+fn exit_ignores_the_bits_above_its_code() {
+    assert_eq!(decode(0x0000_0100), Instruction::Exit(0));
+    assert_eq!(decode(0x0000_01FF), Instruction::Exit(0xFF));
+    assert_eq!(decode(0x0E00_0004), Instruction::ExitStack());
+}
+
+#[test]
+fn unassigned_miscellaneous_selectors_decode_as_reserved() {
+    let reserved = (0x09..=0xFF).map(|sel| 0x0300_0000 | sel).chain((0x05..=0xFF).map(|sel| 0x0E00_0000 | sel));
+    for word in reserved {
+        assert_eq!(decode(word), Instruction::Reserved(word), "{:08x}", word);
+    }
+}
+
+#[test]
+fn reserved_instruction_stops_the_program_with_an_error() {
+    for word in [0x0300_0009, 0x0300_00FF, 0x0E00_0005, 0x0E00_00FF] {
+        let mut vm = machine(&[0xF000_0001, word], &[]);
+        assert_eq!(run_error(&mut vm), format!("reserved instruction {:08x} at PC 1", word));
+    }
+}
+
+// The stack after cmov on 10 20 and `cond`
+fn cmov(cond: u32) -> Vec<u32> {
+    let mut vm = machine(&[0xF000_000A, 0xF000_0014, 0xF000_0000 | cond, 0xAB00_0000, 0x0000_0000], &[]); // push 10, 20, cond; cmov; exit 0
+    vm.run().unwrap();
+    vm.stack()
+}
+
+#[test]
+fn cmov_keeps_the_first_value_on_a_nonzero_condition() {
+    assert_eq!(cmov(1), [10]);
+    assert_eq!(cmov(0x0FFF_FFFF), [10]);
+}
+
+#[test]
+fn cmov_keeps_the_second_value_on_a_zero_condition() {
+    assert_eq!(cmov(0), [20]);
+}
+
+#[test]
+fn cmov_without_three_words_is_an_error() {
+    let mut vm = machine(&[0xF000_0001, 0xF000_0001, 0xAB00_0000], &[]);
+    assert_eq!(run_error(&mut vm), "(cmov) stack underflow: needs two values and a condition");
+}
+
+#[test]
+fn inputeof_is_set_only_after_every_line_is_read() {
+    let mut vm = machine(&[
+        0x0300_0008, // inputeof
+        0x0400_0000, // input
+        0x0300_0008, // inputeof
+        0x0400_0000, // input
+        0x0300_0008, // inputeof
+        0x0000_0000, // exit 0
+    ], &["4", "5"]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [1, 5, 0, 4, 0]);
+}
+
+#[test]
+fn inputeof_on_empty_input_is_set() {
+    let mut vm = machine(&[0x0300_0008, 0x0000_0000], &[]);
+    vm.run().unwrap();
+    assert_eq!(vm.stack(), [1]);
+}
+
+#[test]
+fn counted_string_keeps_a_0x01_byte() {
+    let mut vm = machine(&[0x0580_0010, 0x4000_0001, 0x0000_0000], &["a\x01b"]); // stinput 16 (counted), stprint (counted), exit 0
+    vm.run().unwrap();
+    assert_eq!(vm.output.inner, b"a\x01b");
+}
+
+#[test]
+fn packed_string_loses_a_0x01_byte() {
+    let mut vm = machine(&[0x0500_0010, 0x4000_0000, 0x0000_0000], &["a\x01b"]); // stinput 16, stprint, exit 0
+    vm.run().unwrap();
+
+    // The 0x01 reads as padding; Stprint also writes the terminating zero byte
+    assert_eq!(vm.output.inner, b"ab\0");
+}
+
+#[test]
+fn stepping_returns_each_executed_instruction() {
+    let code = [
+        0xF000_0002, // push 2
+        0xF000_0001, // loop: push 1
+        0x2100_0000, //   sub
+        0x92FF_FFF8, //   nez loop
+        0x0000_0000, // exit 0
+    ];
+    let mut vm = machine(&code, &[]);
+    let mut executed = Vec::new();
+    loop {
+        let (instruction, exit) = vm.step_with_instruction().unwrap();
+        executed.push(instruction);
+        if exit.is_some() {
+            break;
+        }
+    }
+
+    let expected: Vec<Instruction> = [0, 1, 2, 3, 1, 2, 3, 4].iter().map(|&addr| decode(code[addr])).collect();
+    assert_eq!(executed, expected);
+    assert_eq!(executed.last(), Some(&Instruction::Exit(0)));
+}
+
+// Divide by zero inside a subroutine, with a trap handler ending in `handler`
+fn trap_in_subroutine(handler: &[u32]) -> TestMachine {
+    let mut code = vec![
+        0x0C00_0007, // settrap 0 handler
+        0x5000_0008, // call sub
+        0x0000_0000, // exit 0
+        0xF000_0007, // sub: push 7
+        0xF000_0000, //   push 0
+        0x2300_0000, //   div
+        0x6000_0008, //   return 8, popping 7 and 0
+    ];
+    code.extend(handler);
+    let mut vm = machine(&code, &[]);
+    vm.frame_check = FrameCheck::Strict;
+    vm
+}
+
+#[test]
+fn trap_inside_a_subroutine_returns_through_its_own_frame() {
+    let mut vm = trap_in_subroutine(&[0x1000_0004, 0x6000_0000]); // handler: pop 4 (the trap number), return 0
+    assert_eq!(vm.run().unwrap(), 0);
+
+    let stats = vm.stats();
+    assert_eq!((stats.calls, stats.returns), (2, 2));
+    assert_eq!(stats.peak_call_depth, 2);
+}
+
+#[test]
+fn strict_frames_check_a_trap_handler_that_leaves_a_word() {
+    let mut vm = trap_in_subroutine(&[0x1000_0004, 0xF000_0063, 0x0100_0001, 0x6000_0000]); // pop 4, push 99, swap 0 1, return 0
+    assert_eq!(run_error(&mut vm), "(return) unbalanced stack returning to 6: 1 word(s) more than at call");
+}
+
+// A version 3 file whose two-word pool holds constants too wide for push
+fn pooled(code: &[u32]) -> TestMachine {
+    let mut file = vec![VERSIONED_MAGIC, 3, 2];
+    file.extend(code);
+    file.extend([0x1234_5678, 0xFFFF_FFFB]);
+    let mut vm = Machine::with_input_lines(&[], Vec::new());
+    vm.load(&file).unwrap();
+    vm
+}
+
+#[test]
+fn pushconst_reads_each_pooled_constant() {
+    let mut vm = pooled(&[
+        0xA900_0001, // pushconst 1
+        0xD000_0000, // print
+        0xA900_0000, // pushconst 0
+        0xD000_0001, // print (hex)
+        0x0000_0000, // exit 0
+    ]);
+    assert_eq!(vm.run().unwrap(), 0);
+    assert_eq!(output(&vm), "-5\n0x12345678\n");
+}
+
+#[test]
+fn pushconst_past_the_pool_is_an_error() {
+    let mut vm = pooled(&[0xA900_0002, 0x0000_0000]); // pushconst 2, exit 0
+    assert_eq!(run_error(&mut vm), "(const) no constant 2 in a pool of 2");
+}
+
+#[test]
+fn counted_string_longer_than_the_stack_is_an_error() {
+    let mut vm = machine(&[0xF000_0041, 0xF7FF_FFFF, 0x4000_0001], &[]); // push "A", push a 128 MiB count, stprint (counted)
+    assert_eq!(run_error(&mut vm), "(stprint) 134217727-byte string at 1022 runs past the edge of RAM");
+
+    // The one word below the count holds up to four bytes
+    let mut vm = machine(&[0xF000_0041, 0xF000_0005, 0x4000_0001], &[]);
+    assert_eq!(run_error(&mut vm), "(stprint) 5-byte string at 1022 runs past the edge of RAM");
+    let mut vm = machine(&[0xF000_0041, 0xF000_0001, 0x4000_0001, 0x0000_0000], &[]);
+    vm.run().unwrap();
+    assert_eq!(output(&vm), "A");
+}
+
+#[test]
+fn computed_jump_through_return_reports_no_dead_code() {
+    let program = [
+        0xF000_0003, // push 3
+        0x6000_0000, // return 0: a jump to 3
+        0x0000_0001, // exit 1
+        0x0000_0000, // exit 0
+    ];
+    assert_eq!(find_dead_code(&program, 0), []);
+}
+
+#[test]
+fn computed_jump_inside_a_subroutine_reports_no_dead_code() {
+    let program = [
+        0x5000_0008, // call sub
+        0x0000_0000, // exit 0
+        0xF000_0005, // sub: push 5
+        0x6000_0000, //   return 0, through 5 rather than the return address
+        0x0000_0001, // exit 1
+        0x0000_0002, // exit 2
+    ];
+    assert_eq!(find_dead_code(&program, 0), []);
+}
+
+#[test]
+fn balanced_subroutine_still_reports_dead_code() {
+    let program = [
+        0x5000_000C, // call sub
+        0x0000_0000, // exit 0
+        0xF000_0009, // push 9 (never runs)
+        0xF000_0001, // sub: push 1
+        0xD000_0000, //   print
+        0x6000_0004, //   return 4, popping the 1
+    ];
+    assert_eq!(find_dead_code(&program, 0), [2]);
+}
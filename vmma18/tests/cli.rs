@@ -0,0 +1,269 @@
+/* Synopsis: Command-line tests for the VM binary
+ *
+ * Each test runs the built binary on a program from marz/ (or one written
+ * to a temporary file) and checks its exit status, output and any files it
+ * writes.
+ */
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Output, Stdio};
+
+use vmproject::{parse_binary_trace, parse_trace};
+
+// A program from the marz/ directory
+fn marz(name: &str) -> String {
+    format!("{}/marz/{}", env!("CARGO_MANIFEST_DIR"), name)
+}
+
+// A path in the temporary directory, unique to this test run
+fn temp_path(name: &str) -> PathBuf {
+    env::temp_dir().join(format!("vm-cli-{}-{}", std::process::id(), name))
+}
+
+// Run the VM with `args`, feeding it `input`
+fn vm(args: &[&str], input: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_VMproject"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("VM binary should start");
+    // The VM may exit before reading its input, so a broken pipe is not a failure
+    let _ = child.stdin.take().unwrap().write_all(input.as_bytes());
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn ram_dump_holds_all_of_ram() {
+    let path = temp_path("ram-dump");
+    let output = vm(&["--ram-dump", path.to_str().unwrap(), &marz("add.v")], "2\n3\n");
+    assert!(output.status.success(), "{}", stderr(&output));
+
+    // 1024 words of 4 bytes
+    let dump = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(dump.len(), 1024 * 4);
+}
+
+#[test]
+fn unknown_option_before_the_program_is_rejected() {
+    let output = vm(&["--trace-fromat=jsonl", &marz("add.v")], "");
+    assert!(!output.status.success());
+    assert_eq!(output.status.code(), Some(2));
+    assert!(stderr(&output).starts_with("Usage:"), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn binary_trace_needs_a_trace_file() {
+    let output = vm(&["--trace-format=binary", &marz("add.v")], "2\n3\n");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stderr(&output), "Error: --trace-format=binary needs --trace-file\n");
+}
+
+#[test]
+fn binary_trace_reads_back_as_the_jsonl_trace() {
+    let (binary, jsonl) = (temp_path("trace.bin"), temp_path("trace.jsonl"));
+    for (format, path) in [("--trace-format=binary", &binary), ("--trace-format=jsonl", &jsonl)] {
+        let output = vm(&[format, "--trace-file", path.to_str().unwrap(), &marz("add.v")], "2\n3\n");
+        assert!(output.status.success(), "{}", stderr(&output));
+    }
+
+    let records = parse_binary_trace(&fs::read(&binary).unwrap()).unwrap();
+    let steps = parse_trace(&fs::read_to_string(&jsonl).unwrap()).unwrap();
+    fs::remove_file(&binary).unwrap();
+    fs::remove_file(&jsonl).unwrap();
+
+    assert!(!records.is_empty());
+    assert_eq!(records.len(), steps.len());
+    for (record, step) in records.iter().zip(&steps) {
+        assert_eq!((record.pc as i16, record.sp as i16), (step.pc, step.sp));
+    }
+    assert_eq!(records.last().unwrap().opcode, 0, "the last instruction is exit");
+}
+
+// Write `words` (magic word first) to a temporary .vhex program file
+fn hex_program(name: &str, words: &[u32]) -> PathBuf {
+    let path = temp_path(&format!("{}.vhex", name));
+    let text: Vec<String> = words.iter().map(|word| format!("{:08x}", word)).collect();
+    fs::write(&path, text.join("\n")).unwrap();
+    path
+}
+
+#[test]
+fn entry_starts_past_a_data_word() {
+    // Word 0 is data (it would run as `exit 42`); the code starts at word 1
+    let path = hex_program("entry", &[0xEFBE_ADDE, 0x0000_002A, 0xF000_0007, 0xD000_0000, 0x0000_0000]);
+    let from_entry = vm(&["--entry", "1", path.to_str().unwrap()], "");
+    let from_zero = vm(&[path.to_str().unwrap()], "");
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(from_entry.status.code(), Some(0));
+    assert_eq!(stdout(&from_entry), "7\n");
+    assert_eq!(from_zero.status.code(), Some(42));
+}
+
+#[test]
+fn print_exit_reports_the_exit_code() {
+    let path = hex_program("print-exit", &[0xEFBE_ADDE, 0x0000_0007]); // exit 7
+    let output = vm(&["--print-exit", path.to_str().unwrap()], "");
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(7));
+    assert_eq!(stderr(&output), "exit code: 7\n");
+}
+
+#[test]
+fn step_count_stops_and_shows_the_machine() {
+    // push 7, push 5, add, print, exit 0
+    let path = hex_program("step-count", &[0xEFBE_ADDE, 0xF000_0007, 0xF000_0005, 0x2000_0000, 0xD000_0000, 0x0000_0000]);
+    let output = vm(&["--step-count", "3", path.to_str().unwrap()], "");
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "", "print has not run yet");
+    assert_eq!(stderr(&output), "after 3 step(s): pc=3 sp=1023\n  0000: 0000000c\n");
+}
+
+#[test]
+fn stats_summarize_a_loop_with_calls_and_output() {
+    let path = hex_program("stats", &[
+        0xEFBE_ADDE,
+        0xF000_0003, // push 3
+        0x5000_0014, // loop: call show
+        0xF000_0001, // push 1
+        0x2100_0000, // sub
+        0x92FF_FFF4, // nez loop
+        0x0000_0000, // exit 0
+        0xD000_0004, // show: print 4
+        0x6000_0000, // return
+    ]);
+    let output = vm(&["--stats", path.to_str().unwrap()], "");
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(0));
+    assert_eq!(stdout(&output), "3\n2\n1\n");
+    assert_eq!(
+        stderr(&output),
+        "cycles: 20\npeak stack depth: 2\ncalls: 3\nreturns: 3\npeak call depth: 1\noutput bytes: 6\n"
+    );
+}
+
+#[test]
+fn data_file_is_indexed_with_loadabs() {
+    // push 2, loadabs 100, print, exit 0
+    let program = hex_program("data-code", &[0xEFBE_ADDE, 0xF000_0002, 0xB400_0064, 0xD000_0000, 0x0000_0000]);
+    let data = temp_path("data-table");
+    fs::write(&data, [10u32, 20, 30, 40].iter().flat_map(|word| word.to_le_bytes()).collect::<Vec<u8>>()).unwrap();
+
+    let output = vm(&["--data", data.to_str().unwrap(), "--data-addr", "100", program.to_str().unwrap()], "");
+    let overlapping = vm(&["--data", data.to_str().unwrap(), "--data-addr", "2", program.to_str().unwrap()], "");
+    fs::remove_file(&program).unwrap();
+    fs::remove_file(&data).unwrap();
+
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "30\n");
+    assert_eq!(overlapping.status.code(), Some(1));
+    assert!(stderr(&overlapping).contains("overlaps the program (words 0-3)"), "{}", stderr(&overlapping));
+}
+
+#[test]
+fn trace_sample_traces_every_nth_instruction() {
+    // 19 pushes, then exit 0: 20 instructions
+    let mut words = vec![0xEFBE_ADDE];
+    words.extend([0xF000_0001; 19]);
+    words.push(0x0000_0000);
+    let path = hex_program("trace-sample", &words);
+    let output = vm(&["--trace-sample", "5", "--trace-format=jsonl", path.to_str().unwrap()], "");
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(0));
+    let steps = parse_trace(&stderr(&output)).unwrap();
+    let pcs: Vec<i16> = steps.iter().map(|step| step.pc).collect();
+    assert_eq!(pcs, [4, 9, 14, 19]);
+}
+
+#[test]
+fn arguments_after_the_program_reach_argc_and_argv() {
+    let path = hex_program("argv", &[
+        0xEFBE_ADDE,
+        0x0300_0006, // argc
+        0xD000_0000, // print
+        0x1000_0004, // pop
+        0xF000_0000, // push 0
+        0x0300_0007, // argv
+        0x4000_0000, // stprint
+        0xF000_0001, // push 1
+        0x0300_0007, // argv
+        0x4000_0000, // stprint
+        0x0000_0000, // exit 0
+    ]);
+    let output = vm(&[path.to_str().unwrap(), "alpha", "be"], "");
+    fs::remove_file(&path).unwrap();
+
+    // Stprint of a packed string also writes its terminating zero byte
+    assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "2\nalpha\0be\0");
+}
+
+#[test]
+fn quiet_leaves_only_the_programs_own_output() {
+    // push 7, debug, print, exit 0
+    let path = hex_program("quiet", &[0xEFBE_ADDE, 0xF000_0007, 0x0F00_0000, 0xD000_0000, 0x0000_0000]);
+    let loud = vm(&["--trace", "--stats", path.to_str().unwrap()], "");
+    let quiet = vm(&["--quiet", "--trace", "--stats", path.to_str().unwrap()], "");
+    fs::remove_file(&path).unwrap();
+
+    assert!(!stderr(&loud).is_empty());
+    assert_eq!(quiet.status.code(), Some(0));
+    assert_eq!(stderr(&quiet), "");
+    assert_eq!(stdout(&quiet), "7\n");
+}
+
+#[test]
+fn trace_to_a_pipe_has_no_color_escapes() {
+    let output = vm(&["--trace", &marz("add.v")], "2\n3\n");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stderr(&output).contains("exit"), "{}", stderr(&output));
+    assert!(!stderr(&output).contains('\x1b'), "{:?}", stderr(&output));
+}
+
+#[test]
+fn error_report_lists_the_recent_instructions() {
+    // push 1, push 0, div
+    let path = hex_program("recent", &[0xEFBE_ADDE, 0xF000_0001, 0xF000_0000, 0x2300_0000]);
+    let output = vm(&[path.to_str().unwrap()], "");
+    fs::remove_file(&path).unwrap();
+
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        stderr(&output),
+        "Error: (divide) division by zero\nrecent instructions:\n  0000: f0000001  push 1\n  0001: f0000000  push 0\n  0002: 23000000  div\n"
+    );
+}
+
+#[test]
+fn negative_computed_exit_maps_to_a_failing_status() {
+    // push 3, push 5, sub, exitstack: exits with -2
+    let small = hex_program("exit-neg", &[0xEFBE_ADDE, 0xF000_0003, 0xF000_0005, 0x2100_0000, 0x0E00_0004]);
+    // push -1000, exitstack
+    let large = hex_program("exit-neg-large", &[0xEFBE_ADDE, 0xFFFF_FC18, 0x0E00_0004]);
+    let (small_output, large_output) = (vm(&[small.to_str().unwrap()], ""), vm(&[large.to_str().unwrap()], ""));
+    fs::remove_file(&small).unwrap();
+    fs::remove_file(&large).unwrap();
+
+    assert_eq!(small_output.status.code(), Some(254));
+    assert_eq!(large_output.status.code(), Some(128));
+}